@@ -59,7 +59,7 @@ impl Bitmap {
         }
     }
     // 判断某块内存是否已经分配
-    fn is_bit_alloc(&self, idx : usize) -> bool {
+    pub fn is_bit_alloc(&self, idx : usize) -> bool {
         assert!(self.total_cnt > idx);
         unsafe{
             self.addr.add(idx / 8).read_volatile() & (1 << (idx % 8)) != 0
@@ -0,0 +1,198 @@
+//! # 内存管理器配置
+//! 以构建者方式设置页面大小、DMA 与内核区域大小、保留范围与堆内存策略
+//! 通过 MemoryManager::from_config 创建，参数不合理时返回错误而不是得到错误的管理器
+//!
+//! 2021年5月3日 zg
+
+use core::ops::Range;
+
+use crate::{config::PAGE_SIZE, error::ConfigError, heap::HeapPolicy, layout::MemoryLayout,
+    zone::default_dma_page_num};
+
+/// 页面需放得下内存池结构体及其位图
+const MIN_PAGE_SIZE : usize = 1024;
+
+/// ## 内核区域大小
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum KernelShare {
+    /// 页面数
+    Pages(usize),
+    /// 占管理范围的百分比
+    Percent(usize),
+}
+
+pub struct MemoryConfig {
+    pub(crate) layout : MemoryLayout,
+    pub(crate) page_size : usize,
+    kernel_share : KernelShare,
+    /// 未设置时见 default_dma_page_num
+    dma_page_num : Option<usize>,
+    pub(crate) heap : HeapPolicy,
+    /// 保留范围表满时记下，构建时返回
+    reserved_full : bool,
+}
+
+impl MemoryConfig {
+    /// ### 管理 [heap_start, memory_end) 的内存
+    /// 默认页面大小为 PAGE_SIZE，内核区域占一半
+    pub const fn new(heap_start : usize, memory_end : usize)->Self {
+        Self::from_layout(MemoryLayout::new(heap_start, memory_end))
+    }
+
+    /// ### 使用设备树、链接符号得到的内存布局，包括其中的保留范围
+    pub const fn from_layout(layout : MemoryLayout)->Self {
+        Self {
+            layout,
+            page_size : PAGE_SIZE,
+            kernel_share : KernelShare::Percent(50),
+            dma_page_num : None,
+            heap : HeapPolicy::DEFAULT,
+            reserved_full : false,
+        }
+    }
+
+    pub fn page_size(mut self, page_size : usize)->Self {
+        self.page_size = page_size;
+        self
+    }
+
+    pub fn kernel_share(mut self, share : KernelShare)->Self {
+        self.kernel_share = share;
+        self
+    }
+
+    /// ### DMA 区域的页面数，位于管理范围的最低处
+    pub fn dma_pages(mut self, num : usize)->Self {
+        self.dma_page_num = Some(num);
+        self
+    }
+
+    pub fn reserve(mut self, range : Range<usize>)->Self {
+        if self.layout.add_reserved(range).is_err() {
+            self.reserved_full = true;
+        }
+        self
+    }
+
+    /// ### 见 HeapPolicy::too_big
+    pub fn heap_too_big(mut self, size : usize)->Self {
+        self.heap.too_big = size;
+        self
+    }
+
+    /// ### 见 HeapPolicy::size_inside
+    pub fn heap_size_inside(mut self, size : usize)->Self {
+        self.heap.size_inside = size;
+        self
+    }
+
+    /// ### 调试选项，释放的堆内存以固定值填充
+    pub fn poison_free(mut self, poison : bool)->Self {
+        self.heap.poison_free = poison;
+        self
+    }
+
+    /// ### 检查参数
+    /// 页面数组所占的大小由 meta_size 给出，它与页面总数、区域数有关
+    pub fn validate(&self, meta_size : impl Fn(usize)->usize)->Result<(), ConfigError> {
+        let page_size = self.page_size;
+        if !page_size.is_power_of_two() || page_size < MIN_PAGE_SIZE {
+            return Err(ConfigError::PageSize);
+        }
+        let (start, end) = (self.layout.heap_start, self.layout.memory_end);
+        if start % page_size != 0 || end % page_size != 0 {
+            return Err(ConfigError::Unaligned);
+        }
+        if start >= end {
+            return Err(ConfigError::EmptyMemory);
+        }
+        let total_num = (end - start) / page_size;
+        let meta_num = meta_size(total_num).div_ceil(page_size);
+        let dma_num = self.dma_page_num();
+        if dma_num >= total_num {
+            return Err(ConfigError::DmaZone);
+        }
+        let kernel_num = self.kernel_page_num();
+        if kernel_num <= meta_num || kernel_num > total_num - dma_num {
+            return Err(ConfigError::KernelShare);
+        }
+        // 页面数组在内核区域开头
+        let meta_start = start + dma_num * page_size;
+        let meta_end = meta_start + meta_num * page_size;
+        if self.reserved_full || self.layout.reserved().iter()
+                .any(|r| r.start < meta_end && r.end > meta_start) {
+            return Err(ConfigError::ReservedRange);
+        }
+        let heap = &self.heap;
+        if !heap.too_big.is_power_of_two() || !heap.size_inside.is_power_of_two() ||
+                heap.size_inside > page_size {
+            return Err(ConfigError::HeapPolicy);
+        }
+        Ok(())
+    }
+
+    /// ### DMA 区域的页面数
+    pub(crate) fn dma_page_num(&self)->usize {
+        let total_num = self.layout.memory_end.saturating_sub(self.layout.heap_start) / self.page_size;
+        self.dma_page_num.unwrap_or(default_dma_page_num(total_num))
+    }
+
+    /// ### 内核区域的页面数
+    /// 百分比超过 100 时返回 usize::MAX，由 validate 报错
+    pub(crate) fn kernel_page_num(&self)->usize {
+        let total_num = self.layout.memory_end.saturating_sub(self.layout.heap_start) / self.page_size;
+        match self.kernel_share {
+            KernelShare::Pages(num) => num,
+            KernelShare::Percent(percent) if percent <= 100 => total_num * percent / 100,
+            KernelShare::Percent(_) => usize::MAX,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{MemoryOp, config::PAGE_SIZE, error::ConfigError, testing::{Arena, Manager}, zone::ZoneId};
+    use super::{KernelShare, MemoryConfig};
+
+    fn build(config : MemoryConfig)->Result<Manager, ConfigError> {
+        Manager::from_config(&config)
+    }
+
+    #[test]
+    fn from_config() {
+        let arena = Arena::new(1 << 20);
+        let config = MemoryConfig::new(arena.start(), arena.end())
+            .kernel_share(KernelShare::Pages(64))
+            .dma_pages(8)
+            .poison_free(true);
+        let mut mgr = build(config).unwrap();
+        assert_eq!(mgr.free_page_num(ZoneId::DMA), 8);
+        assert_eq!(mgr.free_page_num(ZoneId::USER), 256 - 64 - 8);
+        let addr = mgr.alloc_memory(64, ZoneId::USER).unwrap();
+        mgr.alloc_memory(64, ZoneId::USER).unwrap();
+        mgr.free_memory(addr);
+        // 释放的内存以固定值填充
+        assert_eq!(unsafe {*addr.add(63)}, 0xa5);
+    }
+
+    #[test]
+    fn invalid() {
+        let arena = Arena::new(1 << 20);
+        let (start, end) = (arena.start(), arena.end());
+        let new = || MemoryConfig::new(start, end);
+        assert_eq!(build(new().page_size(3000)).err(), Some(ConfigError::PageSize));
+        assert_eq!(build(new().page_size(512)).err(), Some(ConfigError::PageSize));
+        assert_eq!(build(MemoryConfig::new(start + 8, end)).err(), Some(ConfigError::Unaligned));
+        assert_eq!(build(MemoryConfig::new(end, start)).err(), Some(ConfigError::EmptyMemory));
+        assert_eq!(build(new().kernel_share(KernelShare::Percent(101))).err(), Some(ConfigError::KernelShare));
+        assert_eq!(build(new().kernel_share(KernelShare::Pages(1))).err(), Some(ConfigError::KernelShare));
+        assert_eq!(build(new().kernel_share(KernelShare::Pages(250))).err(), Some(ConfigError::KernelShare));
+        // 页面数组在 DMA 区域之后
+        let meta = start + 32 * PAGE_SIZE;
+        assert_eq!(build(new().reserve(meta..meta + 1)).err(), Some(ConfigError::ReservedRange));
+        assert!(build(new().reserve(start..meta)).is_ok());
+        assert_eq!(build(new().heap_too_big(3000)).err(), Some(ConfigError::HeapPolicy));
+        assert_eq!(build(new().heap_size_inside(2 * PAGE_SIZE)).err(), Some(ConfigError::HeapPolicy));
+    }
+}
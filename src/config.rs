@@ -23,4 +23,21 @@ extern "C" {
 }
 
 pub const PAGE_SIZE : usize = 4096;
-pub const KERNEL_PAGE_NUM : usize = 51200;
\ No newline at end of file
+pub const KERNEL_PAGE_NUM : usize = 51200;
+/// DMA 区域默认的页面数
+pub const DMA_PAGE_NUM : usize = 4096;
+pub const QUOTA_NUM : usize = 64;
+pub const SHRINKER_NUM : usize = 8;
+pub const RESERVE_PAGE_NUM : usize = 16;
+/// 同时交出的保留页面数上限，交出后空位会被补齐，所以多于保留的页面数
+pub const RESERVE_TAKEN_NUM : usize = RESERVE_PAGE_NUM * 2;
+/// 区域数量上限
+pub const ZONE_NUM : usize = 8;
+/// 内存布局中保留范围的数量上限
+pub const RESERVED_RANGE_NUM : usize = 16;
+/// 每级页表索引的位数，order 级大页包含 1 << (HUGE_PAGE_SHIFT * order) 个页面
+pub const HUGE_PAGE_SHIFT : usize = 9;
+/// 空闲段长度直方图的档数，第 i 档统计长度在 [2^i, 2^(i+1)) 的空闲段，最后一档不设上限
+pub const FREE_RUN_ORDER : usize = 16;
+/// 登记的可移动页面数量上限
+pub const MIGRATE_NUM : usize = 64;
\ No newline at end of file
@@ -0,0 +1,120 @@
+//! # DMA 缓冲区
+//! 持有一段 DMA 页面，离开作用域时自动释放
+//!
+//! 2021年5月1日 zg
+
+use crate::require::MemoryOp;
+
+/// ## DMA 缓冲区
+/// 存活期间借用内存管理器，其间通过 manager 使用管理器
+pub struct DmaBuffer<'a, M : MemoryOp> {
+    addr : *mut u8,
+    page_num : usize,
+    manager : &'a mut M,
+}
+
+impl<'a, M : MemoryOp> DmaBuffer<'a, M> {
+    /// ### 申请 DMA 缓冲区
+    /// 参数含义同 MemoryOp::alloc_dma
+    pub fn new(manager : &'a mut M, page_num : usize,
+            max_addr : usize, align : usize)->Option<Self> {
+        let addr = manager.alloc_dma(page_num, max_addr, align)?;
+        Some(Self {
+            addr,
+            page_num,
+            manager,
+        })
+    }
+
+    /// ### 物理地址
+    /// 内核内存为恒等映射，可以直接交给设备
+    pub fn addr(&self)->*mut u8 {
+        self.addr
+    }
+
+    pub fn page_num(&self)->usize {
+        self.page_num
+    }
+
+    /// ### 借用中的内存管理器
+    pub fn manager(&mut self)->&mut M {
+        self.manager
+    }
+}
+
+impl<M : MemoryOp> Drop for DmaBuffer<'_, M> {
+    fn drop(&mut self) {
+        self.manager.free_page(self.addr);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{MemoryOp, builder::MemoryConfig, config::PAGE_SIZE, error::ConfigError,
+        testing::{Arena, Manager}, watermark::Watermark, zone::ZoneId};
+    use super::DmaBuffer;
+
+    #[test]
+    fn buffer() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(64);
+        let free = mgr.free_page_num(ZoneId::DMA);
+        assert_eq!(free, 32);
+        let kernel = mgr.kernel_page(1).unwrap();
+        {
+            let mut buf = DmaBuffer::new(&mut mgr, 4, usize::MAX, 4 * PAGE_SIZE).unwrap();
+            // DMA 区域在最低处
+            assert!(buf.addr() < kernel);
+            assert_eq!(buf.addr() as usize % (4 * PAGE_SIZE), 0);
+            assert_eq!(buf.manager().free_page_num(ZoneId::DMA), free - 4);
+        }
+        assert_eq!(mgr.free_page_num(ZoneId::DMA), free);
+        // 最后一个字节不超过 max_addr
+        let max_addr = arena.start() + 8 * PAGE_SIZE - 1;
+        let addr = mgr.alloc_dma(8, max_addr, PAGE_SIZE).unwrap();
+        assert_eq!(addr as usize, arena.start());
+        assert_eq!(mgr.alloc_dma(1, max_addr, PAGE_SIZE), None);
+        mgr.free_page(addr);
+        mgr.free_page(kernel);
+    }
+
+    #[test]
+    fn no_dma_zone() {
+        let arena = Arena::new(1 << 20);
+        // new 不划分 DMA 区域，内核区域从头开始
+        let mut mgr = Manager::new(arena.start(), 64, PAGE_SIZE, arena.end());
+        assert_eq!(mgr.free_page_num(ZoneId::DMA), 0);
+        assert!(mgr.alloc_dma(1, usize::MAX, PAGE_SIZE).is_none());
+        assert!(DmaBuffer::new(&mut mgr, 1, usize::MAX, PAGE_SIZE).is_none());
+        let kernel = mgr.kernel_page(1).unwrap() as usize;
+        assert!(kernel < arena.start() + 64 * PAGE_SIZE);
+        assert_eq!(mgr.user_page(1).unwrap() as usize, arena.start() + 64 * PAGE_SIZE);
+        assert_eq!(mgr.free_page_num(ZoneId::USER), 256 - 64 - 1);
+    }
+
+    #[test]
+    fn not_lent() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(64);
+        let user_free = mgr.free_page_num(ZoneId::USER);
+        mgr.set_watermark(ZoneId::USER, Watermark::new(user_free, user_free, user_free)).unwrap();
+        let free = mgr.free_page_num(ZoneId::DMA);
+        while mgr.kernel_page(1).is_some() {}
+        assert_eq!(mgr.free_page_num(ZoneId::DMA), free);
+        assert!(mgr.alloc_dma(free, usize::MAX, PAGE_SIZE).is_some());
+    }
+
+    #[test]
+    fn config() {
+        let arena = Arena::new(1 << 20);
+        let config = MemoryConfig::new(arena.start(), arena.end()).dma_pages(16);
+        let mut mgr = Manager::from_config(&config).unwrap();
+        assert_eq!(mgr.free_page_num(ZoneId::DMA), 16);
+        assert_eq!(mgr.alloc_dma(1, usize::MAX, PAGE_SIZE).unwrap() as usize, arena.start());
+        let config = MemoryConfig::new(arena.start(), arena.end()).dma_pages(256);
+        assert_eq!(Manager::from_config(&config).err(), Some(ConfigError::DmaZone));
+        let config = MemoryConfig::new(arena.start(), arena.end()).dma_pages(200);
+        assert_eq!(Manager::from_config(&config).err(), Some(ConfigError::KernelShare));
+    }
+}
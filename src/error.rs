@@ -0,0 +1,53 @@
+//! # 错误类型
+//! 
+//! 2021年4月23日 zg
+
+/// ## 内存申请错误
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MemoryError {
+    /// 没有足够的空闲内存
+    OutOfMemory,
+    /// 超出所有者的配额
+    OverQuota,
+    /// 配额表已满，无法再设置新的配额
+    QuotaTableFull,
+    /// 回收回调已注册满
+    ShrinkerTableFull,
+    /// 设备树格式错误，或其中找不到管理范围所在的内存
+    InvalidDeviceTree,
+    /// 内存布局的保留范围表已满
+    ReservedTableFull,
+    /// 内存布局中的段无序、重叠或超出物理内存，或者放不下页面数组
+    InvalidLayout,
+    /// 热插入的内存与已管理的内存重叠、过小，或区域不存在
+    InvalidRegion,
+    /// 可移动页面登记表已满
+    MigrateTableFull,
+    /// 地址不是一段已分配页面的起始地址
+    InvalidPage,
+    /// 申请的页面数为 0，或对齐既不是 2 的幂也不是页面大小的整数倍
+    InvalidRequest,
+    /// 区域编号超出已划分的区域
+    InvalidZone,
+    /// 水位不满足 min <= low <= high
+    InvalidWatermark,
+}
+
+/// ## 内存管理器配置错误
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ConfigError {
+    /// 页面大小不是 2 的幂或过小
+    PageSize,
+    /// 管理范围的起点或终点没有按页面对齐
+    Unaligned,
+    /// 管理范围为空
+    EmptyMemory,
+    /// DMA 区域占满了整个管理范围
+    DmaZone,
+    /// 内核区域为空、超出管理范围或放不下页面数组
+    KernelShare,
+    /// 保留范围与页面数组重叠，或保留范围过多
+    ReservedRange,
+    /// 堆内存策略的参数不是 2 的幂，或 size_inside 超过页面大小
+    HeapPolicy,
+}
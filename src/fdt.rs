@@ -0,0 +1,345 @@
+//! # 设备树解析
+//! 只读取内存布局需要的部分：内存保留块、/memory 节点、/reserved-memory 的子节点
+//! 数据均为大端序
+//!
+//! 2021年5月2日 zg
+
+use core::{ops::Range, slice::from_raw_parts};
+
+use crate::error::MemoryError;
+
+const FDT_MAGIC : u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE : u32 = 1;
+const FDT_END_NODE : u32 = 2;
+const FDT_PROP : u32 = 3;
+const FDT_NOP : u32 = 4;
+const FDT_END : u32 = 9;
+/// 解析时记录的最大节点深度
+const MAX_DEPTH : usize = 16;
+
+/// ## 设备树中描述的内存范围
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Region {
+    /// 物理内存
+    Memory,
+    /// 不可使用的内存，包括设备树自身
+    Reserved,
+}
+
+pub struct Fdt {
+    base : usize,
+    size : usize,
+}
+
+impl Fdt {
+    pub fn new(addr : usize)->Result<Self, MemoryError> {
+        let mut rt = Self { base : addr, size : 8 };
+        if rt.u32_at(0)? != FDT_MAGIC {
+            return Err(MemoryError::InvalidDeviceTree);
+        }
+        rt.size = rt.u32_at(4)? as usize;
+        Ok(rt)
+    }
+
+    /// ### 遍历设备树中的内存范围
+    pub fn for_each_region(&self, f : &mut impl FnMut(Region, Range<usize>))->Result<(), MemoryError> {
+        f(Region::Reserved, range(self.base, self.size)?);
+        // 内存保留块，以地址、大小均为 0 的项结束
+        let mut off = self.u32_at(16)? as usize;
+        loop {
+            let addr = self.u64_at(off)? as usize;
+            let size = self.u64_at(off + 8)? as usize;
+            if addr == 0 && size == 0 {
+                break;
+            }
+            f(Region::Reserved, range(addr, size)?);
+            off += 16;
+        }
+        self.walk(f)
+    }
+
+    /// ### 遍历结构块
+    /// 规范要求属性出现在子节点之前，因此读到 reg 时所需的 cells 已经确定
+    fn walk(&self, f : &mut impl FnMut(Region, Range<usize>))->Result<(), MemoryError> {
+        let strings = self.u32_at(12)? as usize;
+        let mut off = self.u32_at(8)? as usize;
+        // 每一层节点的类型，以及为其子节点规定的 #address-cells、#size-cells
+        let mut kind = [None; MAX_DEPTH];
+        let mut cells = [(2, 1); MAX_DEPTH];
+        let mut depth = 0;
+        loop {
+            let token = self.u32_at(off)?;
+            off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = self.str_at(off)?;
+                    off = align4(off + name.len() + 1);
+                    if depth + 1 >= MAX_DEPTH {
+                        return Err(MemoryError::InvalidDeviceTree);
+                    }
+                    depth += 1;
+                    cells[depth] = (2, 1);
+                    // reserved-memory 节点本身没有 reg，标记后用于识别其子节点
+                    kind[depth] = match depth {
+                        2 if name == b"memory" || name.starts_with(b"memory@") => Some(Region::Memory),
+                        2 if name == b"reserved-memory" => Some(Region::Reserved),
+                        3 if kind[2] == Some(Region::Reserved) => Some(Region::Reserved),
+                        _ => None,
+                    };
+                }
+                FDT_END_NODE => {
+                    if depth == 0 {
+                        return Err(MemoryError::InvalidDeviceTree);
+                    }
+                    depth -= 1;
+                }
+                FDT_PROP => {
+                    let len = self.u32_at(off)? as usize;
+                    let name = self.str_at(strings + self.u32_at(off + 4)? as usize)?;
+                    let value = off + 8;
+                    off = align4(value + len);
+                    match name {
+                        b"#address-cells" => cells[depth].0 = self.u32_at(value)? as usize,
+                        b"#size-cells" => cells[depth].1 = self.u32_at(value)? as usize,
+                        b"reg" if depth == 2 && kind[2] == Some(Region::Memory) ||
+                                depth == 3 && kind[3] == Some(Region::Reserved) => {
+                            let (addr_cells, size_cells) = cells[depth - 1];
+                            let entry = (addr_cells + size_cells) * 4;
+                            if entry == 0 {
+                                return Err(MemoryError::InvalidDeviceTree);
+                            }
+                            for i in 0..len / entry {
+                                let addr = self.cells_at(value + i * entry, addr_cells)?;
+                                let size = self.cells_at(value + i * entry + addr_cells * 4, size_cells)?;
+                                f(kind[depth].unwrap(), range(addr, size)?);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                FDT_NOP => {}
+                FDT_END => return Ok(()),
+                _ => return Err(MemoryError::InvalidDeviceTree),
+            }
+        }
+    }
+
+    fn u32_at(&self, off : usize)->Result<u32, MemoryError> {
+        match off.checked_add(4) {
+            Some(end) if end <= self.size => {}
+            _ => return Err(MemoryError::InvalidDeviceTree),
+        }
+        let mut rt = 0;
+        for i in 0..4 {
+            rt = rt << 8 | unsafe {*((self.base + off + i) as *const u8)} as u32;
+        }
+        Ok(rt)
+    }
+
+    fn u64_at(&self, off : usize)->Result<u64, MemoryError> {
+        Ok((self.u32_at(off)? as u64) << 32 | self.u32_at(off + 4)? as u64)
+    }
+
+    /// ### 读取由 num 个 cell 组成的数
+    /// 超过两个 cell 或超出地址空间时返回错误，不截断
+    fn cells_at(&self, off : usize, num : usize)->Result<usize, MemoryError> {
+        if num > 2 {
+            return Err(MemoryError::InvalidDeviceTree);
+        }
+        let mut rt = 0;
+        for i in 0..num {
+            rt = rt << 32 | self.u32_at(off + i * 4)? as u64;
+        }
+        if rt > usize::MAX as u64 {
+            return Err(MemoryError::InvalidDeviceTree);
+        }
+        Ok(rt as usize)
+    }
+
+    /// ### 以 0 结尾的字符串，不含结尾的 0
+    fn str_at(&self, off : usize)->Result<&[u8], MemoryError> {
+        let mut len = 0;
+        while off + len < self.size {
+            if unsafe {*((self.base + off + len) as *const u8)} == 0 {
+                return Ok(unsafe {from_raw_parts((self.base + off) as *const u8, len)});
+            }
+            len += 1;
+        }
+        Err(MemoryError::InvalidDeviceTree)
+    }
+}
+
+/// ### 起始地址与大小给出的范围，超出地址空间时返回错误
+fn range(addr : usize, size : usize)->Result<Range<usize>, MemoryError> {
+    match addr.checked_add(size) {
+        Some(end) => Ok(addr..end),
+        None => Err(MemoryError::InvalidDeviceTree),
+    }
+}
+
+fn align4(x : usize)->usize {
+    (x + 3) & !3
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::vec::Vec;
+
+    use crate::{MemoryOp, config::PAGE_SIZE, error::MemoryError, layout::MemoryLayout, testing::{Arena, Manager}};
+
+    /// ## 生成设备树
+    /// 根节点的 #address-cells 为 2、#size-cells 为 1
+    struct Builder {
+        node : Vec<u8>,
+        string : Vec<u8>,
+    }
+
+    impl Builder {
+        fn u32(&mut self, val : u32) {
+            self.node.extend_from_slice(&val.to_be_bytes());
+        }
+
+        fn pad(&mut self) {
+            while !self.node.len().is_multiple_of(4) {
+                self.node.push(0);
+            }
+        }
+
+        fn begin(&mut self, name : &str) {
+            self.u32(super::FDT_BEGIN_NODE);
+            self.node.extend_from_slice(name.as_bytes());
+            self.node.push(0);
+            self.pad();
+        }
+
+        fn end(&mut self) {
+            self.u32(super::FDT_END_NODE);
+        }
+
+        fn prop(&mut self, name : &str, val : &[u8]) {
+            let off = self.string.len() as u32;
+            self.string.extend_from_slice(name.as_bytes());
+            self.string.push(0);
+            self.u32(super::FDT_PROP);
+            self.u32(val.len() as u32);
+            self.u32(off);
+            self.node.extend_from_slice(val);
+            self.pad();
+        }
+
+        fn cells(&mut self) {
+            self.prop("#address-cells", &2u32.to_be_bytes());
+            self.prop("#size-cells", &1u32.to_be_bytes());
+        }
+    }
+
+    /// ### 两个 cell 的地址与一个 cell 的大小
+    fn reg(addr : usize, size : usize)->Vec<u8> {
+        let mut rt = (addr as u64).to_be_bytes().to_vec();
+        rt.extend_from_slice(&(size as u32).to_be_bytes());
+        rt
+    }
+
+    /// ### memory 为 /memory 节点，rsv 为内存保留块，child 为 /reserved-memory 的子节点
+    fn build(memory : (usize, usize), rsv : (usize, usize), child : (usize, usize))->Vec<u8> {
+        let mut b = Builder { node : Vec::new(), string : Vec::new() };
+        b.begin("");
+        b.cells();
+        b.begin("cpus");
+        b.prop("reg", &reg(1, 2));
+        b.end();
+        b.begin("memory@80000000");
+        b.prop("device_type", b"memory\0");
+        b.prop("reg", &reg(memory.0, memory.1));
+        b.end();
+        b.begin("reserved-memory");
+        b.cells();
+        b.prop("ranges", &[]);
+        b.begin("sbi@80000000");
+        b.prop("reg", &reg(child.0, child.1));
+        b.end();
+        // 没有 reg 的动态保留区不计入
+        b.begin("dynamic");
+        b.prop("size", &4096u32.to_be_bytes());
+        b.end();
+        b.end();
+        b.end();
+        b.u32(super::FDT_END);
+        let mut map = Vec::new();
+        for val in [rsv.0 as u64, rsv.1 as u64, 0, 0] {
+            map.extend_from_slice(&val.to_be_bytes());
+        }
+        let off_map = 40;
+        let off_node = off_map + map.len();
+        let off_string = off_node + b.node.len();
+        let total = off_string + b.string.len();
+        let mut rt = Vec::new();
+        for val in [super::FDT_MAGIC, total as u32, off_node as u32, off_string as u32, off_map as u32,
+                17, 16, 0, b.string.len() as u32, b.node.len() as u32] {
+            rt.extend_from_slice(&val.to_be_bytes());
+        }
+        rt.extend(map);
+        rt.extend(b.node);
+        rt.extend(b.string);
+        rt
+    }
+
+    #[test]
+    fn layout() {
+        let arena = Arena::new(1 << 20);
+        let base = arena.start();
+        let blob = build((base - PAGE_SIZE, arena.end() - base + PAGE_SIZE),
+            (base + 0x40000, 0x2000), (base + 0x60000, 0x1000));
+        let fdt = blob.as_ptr() as usize;
+        let layout = MemoryLayout::from_fdt(fdt, base).unwrap();
+        assert_eq!(layout.memory_end, arena.end());
+        // 设备树自身不在管理范围内
+        assert_eq!(layout.reserved(), [base + 0x40000..base + 0x42000, base + 0x60000..base + 0x61000]);
+        let mut mgr = Manager::from_layout(&layout, PAGE_SIZE).unwrap();
+        let mut page = Vec::new();
+        while let Some(addr) = mgr.user_page(1) {
+            page.push(addr as usize);
+        }
+        while let Some(addr) = mgr.kernel_page(1) {
+            page.push(addr as usize);
+        }
+        for addr in [0x40000, 0x41000, 0x60000] {
+            assert!(!page.contains(&(base + addr)));
+        }
+        assert_eq!(MemoryLayout::from_fdt(fdt, PAGE_SIZE).err(), Some(MemoryError::InvalidDeviceTree));
+    }
+
+    #[test]
+    fn invalid() {
+        let arena = Arena::new(1 << 20);
+        let base = arena.start();
+        let memory = (base, arena.end() - base);
+        let mut blob = build(memory, (base + 0x40000, 0x2000), (base + 0x60000, 0x1000));
+        blob[0] = 0;
+        assert_eq!(MemoryLayout::from_fdt(blob.as_ptr() as usize, base).err(),
+            Some(MemoryError::InvalidDeviceTree));
+        // 地址加大小超出地址空间
+        let blob = build(memory, (usize::MAX - 0xfff, 0x2000), (base + 0x60000, 0x1000));
+        assert_eq!(MemoryLayout::from_fdt(blob.as_ptr() as usize, base).err(),
+            Some(MemoryError::InvalidDeviceTree));
+        // 保留范围与内核区域开头的页面数组重叠
+        let blob = build(memory, (base + 0x40000, 0x2000), (base + 32 * PAGE_SIZE, 0x1000));
+        let layout = MemoryLayout::from_fdt(blob.as_ptr() as usize, base).unwrap();
+        assert_eq!(Manager::from_layout(&layout, PAGE_SIZE).err(), Some(MemoryError::InvalidLayout));
+        // 放不下页面数组
+        let layout = MemoryLayout::new(base, base + 2 * PAGE_SIZE);
+        assert_eq!(Manager::from_layout(&layout, PAGE_SIZE).err(), Some(MemoryError::InvalidLayout));
+    }
+
+    #[test]
+    fn too_many_cells() {
+        let arena = Arena::new(1 << 20);
+        let base = arena.start();
+        let blob = build((base, arena.end() - base), (base + 0x40000, 0x2000), (base + 0x60000, 0x1000));
+        let fdt = super::Fdt::new(blob.as_ptr() as usize).unwrap();
+        // 头部的 magic 与总大小组成两个 cell 的数
+        assert_eq!(fdt.cells_at(0, 2), Ok((super::FDT_MAGIC as usize) << 32 | blob.len()));
+        assert_eq!(fdt.cells_at(0, 3), Err(MemoryError::InvalidDeviceTree));
+    }
+}
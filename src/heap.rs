@@ -1,25 +1,67 @@
 //! # 堆内存分配器
 //! 将内存按照 2 的幂次对齐后进行分配
-//! 分为内核、用户两种内存
+//! 每个区域各有一条内存池链表
 //! 
 //! 2021年1月25日 zg
 
+/// ## 堆内存策略
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct HeapPolicy {
+    /// 块大于此值时内存池只申请容纳一块的页面，否则按四块申请
+    pub too_big : usize,
+    /// 块小于此值时内存池结构体放在自身页面内，否则另外存放
+    pub size_inside : usize,
+    /// 调试用，释放时以 POISON 填充，便于发现释放后继续使用
+    pub poison_free : bool,
+}
+
+impl HeapPolicy {
+    pub const DEFAULT : Self = Self {
+        too_big : MEMORY_TOO_BIG,
+        size_inside : MEMORY_SIZE_INSIDE,
+        poison_free : false,
+    };
+}
+
 pub struct Heap<T:PageOp> {
     page_manager : T,
-    user_allocator : Option<*mut MemoryPool>,
-    kernel_allocator : Option<*mut MemoryPool>,
+    policy : HeapPolicy,
+    /// 按区域编号存放的内存池链表
+    allocator : [Option<*mut MemoryPool>; ZONE_NUM],
+    #[cfg(feature = "leak-tracker")]
+    tracker : LeakTracker,
 }
 
 impl<T:PageOp> Heap<T> {
-    fn alloc(&mut self, size : usize, is_kernel : bool)->Option<*mut u8> {
+    /// ### 对外的申请入口
+    /// 开启 leak-tracker 时在此记录申请位置，内部申请（如内存池结构体）不记录
+    #[cfg_attr(feature = "leak-tracker", track_caller)]
+    fn alloc_record(&mut self, size : usize, zone : ZoneId, tag : Tag,
+            owner : OwnerId)->Option<*mut u8> {
+        let rt = self.alloc(size, zone, PoolKind::new(tag, owner, false));
+        #[cfg(feature = "leak-tracker")]
+        if let Some(addr) = rt {
+            self.tracker.insert(&mut self.page_manager, addr, align(size),
+                tag, Location::caller());
+        }
+        rt
+    }
+
+    fn free_record(&mut self, addr : *mut u8, zone : ZoneId) {
+        #[cfg(feature = "leak-tracker")]
+        self.tracker.remove(addr);
+        self.free(addr, zone);
+    }
+
+    fn alloc(&mut self, size : usize, zone : ZoneId, kind : PoolKind)->Option<*mut u8> {
         let size = align(size);
         let rt;
-        if let Some(node) = self.find_first_contain(size, is_kernel) {
+        if let Some(node) = self.find_first_contain(size, zone, kind) {
             rt = node;
         }
         // 没有足够空间，申请新的
         else {
-            rt = self.create_pool(size, is_kernel).unwrap();
+            rt = self.create_pool(size, zone, kind)?;
         }
         let rt = unsafe{&mut *(rt)};
         if let Some(idx) = rt.bitmap.alloc_bitmap() {
@@ -32,23 +74,25 @@ impl<T:PageOp> Heap<T> {
         }
     }
 
-    fn create_pool(&mut self, size : usize, is_kernel : bool)->Option<*mut MemoryPool> {
+    fn create_pool(&mut self, size : usize, zone : ZoneId, kind : PoolKind)->Option<*mut MemoryPool> {
         let num_alloc = self.decide_page_num(size);
-        let bit_addr;
         let struct_addr;
         let free_cnt;
         let total_size = num_alloc * self.page_manager.page_size();
-        let struct_size = (total_size / size + 7) / 8 + size_of::<MemoryPool>();
-        let phy_addr;
-        if is_kernel {
-            phy_addr = self.page_manager.alloc_kernel_page(num_alloc).unwrap();
-        }
-        else{
-            phy_addr = self.page_manager.alloc_user_page(num_alloc).unwrap();
-        }
-        // 块的粒度较大时另外存放结构体
-        if size >= struct_size * 2 && align(struct_size) != size {
-            struct_addr = self.alloc(struct_size, is_kernel).unwrap();
+        let struct_size = (total_size / size).div_ceil(8) + size_of::<MemoryPool>();
+        let phy_addr = self.page_manager.alloc_page(num_alloc, zone)?;
+        self.page_manager.set_tag(phy_addr, kind.tag);
+        self.page_manager.set_owner(phy_addr, kind.owner);
+        self.page_manager.set_flag(phy_addr, PageBit::HeapPool);
+        // 块的粒度较大时另外存放结构体，结构体本身不能移动
+        if self.is_struct_outside(size, struct_size) {
+            match self.alloc(struct_size, zone, PoolKind { movable : false, ..kind }) {
+                Some(addr) => struct_addr = addr,
+                None => {
+                    self.page_manager.free_page(phy_addr);
+                    return None;
+                }
+            }
             free_cnt = total_size / size;
         }
         // 如果较小，则直接放置在申请的页表内
@@ -56,20 +100,18 @@ impl<T:PageOp> Heap<T> {
             struct_addr = phy_addr;
             free_cnt = (total_size - struct_size) / size;
         }
-        bit_addr = struct_addr as usize + size_of::<MemoryPool>();
+        let bit_addr = struct_addr as usize + size_of::<MemoryPool>();
         let t = struct_addr as *mut MemoryPool;
         unsafe {
-            (*t).init(phy_addr as *mut u8,total_size,
-                size, bit_addr as *mut u8, free_cnt);
-            self.append(t, is_kernel);
+            (*t).init(phy_addr,total_size,
+                size, bit_addr as *mut u8, free_cnt, kind);
+            self.append(t, zone);
         }
         Some(t)
     }
 
-    fn free(&mut self, addr : *mut u8, is_kernel : bool) {
-        let mut head;
-        if is_kernel {head = self.kernel_allocator;}
-        else {head = self.user_allocator;}
+    fn free(&mut self, addr : *mut u8, zone : ZoneId) {
+        let mut head = self.allocator[zone.val()];
 
         let node;
         unsafe {
@@ -78,59 +120,140 @@ impl<T:PageOp> Heap<T> {
             }
             (*head.unwrap()).free_bitmap(addr);
             node = &mut *head.unwrap();
+            if self.policy.poison_free {
+                addr.write_bytes(POISON, node.size);
+            }
         }
         // 如果同大小空内存池太多，释放掉此内存池
         if node.bitmap.use_cnt == 0 {
             let size = node.size;
-            let free_cnt = self.get_free_block_num(size, is_kernel);
-            let use_cnt = self.get_used_block_num(size, is_kernel);
+            let kind = node.kind;
+            let free_cnt = self.get_free_block_num(size, zone, kind);
+            let use_cnt = self.get_used_block_num(size, zone, kind);
             if free_cnt <= 1 || free_cnt * 2 <= use_cnt { return; }
 
-            // 如果块结构体在自己管理的页表内
-            if node.is_inside() {
-                self.page_manager.free_page(head.unwrap() as *mut u8);
+            self.release_pool(head.unwrap(), zone);
+        }
+    }
+
+    /// ### 移除内存池并归还其页面
+    /// 页面被固定或共享时保留内存池，返回 false
+    fn release_pool(&mut self, pool : *mut MemoryPool, zone : ZoneId)->bool {
+        if !self.page_manager.can_move(unsafe {(*pool).physic_base}) {
+            return false;
+        }
+        // 先从链表中移除，结构体所在的内存释放后不能再访问
+        self.remove_pool(pool, zone);
+        let node = unsafe {&*pool};
+        // 如果块结构体在自己管理的页表内
+        if node.is_struct_inside() {
+            self.page_manager.free_page(pool as *mut u8);
+        }
+        else {
+            self.page_manager.free_page(node.physic_base);
+            self.free(pool as *mut u8, zone);
+        }
+        true
+    }
+
+    /// ### 找到可以搬空的可移动内存池
+    /// 已经空闲，或者足够稀疏且同类更满的内存池放得下其中所有的块
+    /// 页面被固定或共享的内存池不能归还，跳过
+    fn find_sparse(&self, zone : ZoneId)->Option<*mut MemoryPool> {
+        let mut head = self.allocator[zone.val()];
+        while let Some(pool) = head {
+            let node = unsafe {&*pool};
+            let sparse = node.live_cnt() == 0 ||
+                node.is_sparse() && self.room_for(pool, zone) >= node.live_cnt();
+            if node.kind.movable && sparse && self.page_manager.can_move(node.physic_base) {
+                return Some(pool);
+            }
+            head = node.next;
+        }
+        None
+    }
+
+    /// ### 比 src 更满的同类内存池中剩余的块数
+    fn room_for(&self, src : *mut MemoryPool, zone : ZoneId)->usize {
+        let mut head = self.allocator[zone.val()];
+        let mut cnt = 0;
+        while let Some(pool) = head {
+            let node = unsafe {&*pool};
+            if node.is_denser(src) {
+                cnt += node.bitmap.free_cnt;
+            }
+            head = node.next;
+        }
+        cnt
+    }
+
+    /// ### 将 src 中的块全部搬到更满的同类内存池，并更新句柄槽位
+    fn evacuate(&mut self, src : *mut MemoryPool, zone : ZoneId) {
+        let node = unsafe {&mut *src};
+        let size = node.size;
+        for idx in node.meta_cnt()..node.bitmap.total_cnt {
+            if !node.bitmap.is_bit_alloc(idx) {
+                continue;
             }
-            else {
-                self.page_manager.free_page(node.physic_base);
-                self.free(head.unwrap() as *mut u8, is_kernel);
+            let mut head = self.allocator[zone.val()];
+            while !unsafe {(*head.unwrap()).is_denser(src) && (*head.unwrap()).bitmap.free_cnt > 0} {
+                head = unsafe {(*head.unwrap()).next};
             }
-            self.remove_pool(head.unwrap(), is_kernel);
+            let dst = unsafe {&mut *head.unwrap()};
+            let to_idx = dst.bitmap.alloc_bitmap().unwrap();
+            let from = (node.physic_base as usize + idx * size) as *mut u8;
+            let to = (dst.physic_base as usize + to_idx * size) as *mut u8;
+            unsafe {
+                from.copy_to_nonoverlapping(to, size);
+                let slot = *(to as *mut *mut *mut u8);
+                *slot = to.add(MOVABLE_HEADER);
+            }
+            node.bitmap.free(idx);
         }
     }
 
-    fn find_first_contain(&self, size : usize, is_kernel : bool)->Option<*mut MemoryPool> {
-        let mut head;
-        if is_kernel { head = self.kernel_allocator; }
-        else { head = self.user_allocator; }
+    fn find_first_contain(&self, size : usize, zone : ZoneId, kind : PoolKind)->Option<*mut MemoryPool> {
+        let mut head = self.allocator[zone.val()];
 
-        while head.is_some() && !unsafe{(*head.unwrap()).can_contain(size)} {
+        while head.is_some() && !unsafe{(*head.unwrap()).can_contain(size, kind)} {
             head = unsafe{(*head.unwrap()).next};
         }
         head
     }
 
+    /// ### 申请 size 字节时分到的块大小
+    /// 同类内存池中有空位时使用其中的块，可能比 size 对齐后更大
+    fn block_size_of(&self, size : usize, zone : ZoneId, kind : PoolKind)->usize {
+        let size = align(size);
+        match self.find_first_contain(size, zone, kind) {
+            Some(pool) => unsafe {(*pool).size},
+            None => size,
+        }
+    }
+
+    /// ### 申请 size 字节时最多需要新建内存池的页面数
+    fn page_need_of(&self, size : usize, zone : ZoneId, kind : PoolKind)->usize {
+        match self.find_first_contain(align(size), zone, kind) {
+            Some(_) => 0,
+            None => self.pool_page_num(size),
+        }
+    }
+
     fn decide_page_num(&self, size : usize) -> usize{
         let page_size = self.page_manager.page_size();
-        let too_big = MEMORY_TOO_BIG;
+        let too_big = self.policy.too_big;
         if size > too_big {
-            (size + page_size - 1) / page_size
+            size.div_ceil(page_size)
         }
         else {
-            (size * 4 + page_size - 1) / page_size
+            (size * 4).div_ceil(page_size)
         }
     }
 
-    fn append(&mut self, pool : *mut MemoryPool, is_kernel : bool) {
-        let mut head;
-        if is_kernel { head = self.kernel_allocator; }
-        else { head = self.user_allocator; }
+    fn append(&mut self, pool : *mut MemoryPool, zone : ZoneId) {
+        let mut head = self.allocator[zone.val()];
         if head.is_none() {
-            if is_kernel {
-                self.kernel_allocator = Some(pool);
-            }
-            else {
-                self.user_allocator = Some(pool);
-            }
+            self.allocator[zone.val()] = Some(pool);
             return;
         }
 
@@ -149,10 +272,8 @@ impl<T:PageOp> Heap<T> {
         }
     }
 
-    fn get_free_block_num(&self, size : usize, is_kernel : bool)->usize {
-        let mut head;
-        if is_kernel { head = self.kernel_allocator; }
-        else { head = self.user_allocator; }
+    fn get_free_block_num(&self, size : usize, zone : ZoneId, kind : PoolKind)->usize {
+        let mut head = self.allocator[zone.val()];
 
         let mut cnt = 0;
 
@@ -161,7 +282,7 @@ impl<T:PageOp> Heap<T> {
                 head = (*head.unwrap()).next;
             }
             while head.is_some() && (*head.unwrap()).size == size {
-                if (*head.unwrap()).bitmap.use_cnt == 0 {
+                if (*head.unwrap()).kind == kind && (*head.unwrap()).bitmap.use_cnt == 0 {
                     cnt += 1;
                 }
                 head = (*head.unwrap()).next;
@@ -170,10 +291,8 @@ impl<T:PageOp> Heap<T> {
         cnt
     }
 
-    fn get_used_block_num(&self, size : usize, is_kernel : bool)->usize {
-        let mut head;
-        if is_kernel { head = self.kernel_allocator; }
-        else { head = self.user_allocator; }
+    fn get_used_block_num(&self, size : usize, zone : ZoneId, kind : PoolKind)->usize {
+        let mut head = self.allocator[zone.val()];
 
         let mut cnt = 0;
     
@@ -182,7 +301,7 @@ impl<T:PageOp> Heap<T> {
                 head = (*head.unwrap()).next;
             }
             while head.is_some() && (*head.unwrap()).size == size {
-                if (*head.unwrap()).bitmap.use_cnt != 0 {
+                if (*head.unwrap()).kind == kind && (*head.unwrap()).bitmap.use_cnt != 0 {
                     cnt += 1;
                 }
                 head = (*head.unwrap()).next;
@@ -191,12 +310,14 @@ impl<T:PageOp> Heap<T> {
         cnt
     }
 
-    fn remove_pool(&mut self, node : *mut MemoryPool, is_kernel : bool) {
-        let mut head;
-        if is_kernel { head = self.kernel_allocator; }
-        else { head = self.user_allocator; }
+    fn remove_pool(&mut self, node : *mut MemoryPool, zone : ZoneId) {
+        let mut head = self.allocator[zone.val()];
 
         unsafe {
+            if head.unwrap() == node {
+                self.allocator[zone.val()] = (*node).next;
+                return;
+            }
             while (*head.unwrap()).next.unwrap() != node {
                 head = (*head.unwrap()).next;
             }
@@ -205,6 +326,34 @@ impl<T:PageOp> Heap<T> {
         }
     }
 
+    /// ### 统计 zone 中各区域满足条件的内存池中正在使用的字节数
+    /// 内存池结构体另外存放时，占用的是同标签、同所有者内存池中的块，需扣除
+    fn used_size_of(&self, zone : Range<usize>, f : impl Fn(&MemoryPool)->bool)->usize {
+        let mut used = 0;
+        let mut meta = 0;
+        for mut head in self.allocator[zone].iter().copied() {
+            unsafe {
+                while head.is_some() {
+                    let t = &(*head.unwrap());
+                    if f(t) {
+                        used += t.used_size();
+                        if !t.is_struct_inside() {
+                            meta += align(t.struct_size());
+                        }
+                    }
+                    head = t.next;
+                }
+            }
+        }
+        used - meta
+    }
+
+    /// ### 内存池结构体是否另外存放
+    /// 结构体与块大小相同时另外存放会递归地新建同样的内存池，只能放在内部
+    fn is_struct_outside(&self, size : usize, struct_size : usize)->bool {
+        size >= self.policy.size_inside && size >= struct_size * 2 && align(struct_size) != size
+    }
+
     fn clear(&mut self, addr : *mut u8, size : usize) {
         unsafe {
             addr.write_bytes(0, size);
@@ -216,31 +365,158 @@ impl<T:PageOp> HeapOp<T> for Heap<T> {
     fn new<'a>(page : T)->Self {
         Self {
             page_manager : page,
-            kernel_allocator : None,
-            user_allocator : None,
+            policy : HeapPolicy::DEFAULT,
+            allocator : [None; ZONE_NUM],
+            #[cfg(feature = "leak-tracker")]
+            tracker : LeakTracker::new(),
         }
     }
 
-    fn alloc_kernel_memory(&mut self, size : usize)->Option<*mut u8> {
-        let rt = self.alloc(size, true);
-        rt
+    #[cfg_attr(feature = "leak-tracker", track_caller)]
+    fn alloc_memory(&mut self, size : usize, zone : ZoneId)->Option<*mut u8> {
+        self.alloc_record(size, zone, Tag::NONE, NO_OWNER)
     }
 
-    fn alloc_user_memory(&mut self, size : usize)->Option<*mut u8> {
-        let rt = self.alloc(size, false);
-        rt
+    #[cfg_attr(feature = "leak-tracker", track_caller)]
+    fn alloc_tagged_memory(&mut self, size : usize, tag : Tag)->Option<*mut u8> {
+        self.alloc_record(size, ZoneId::KERNEL, tag, NO_OWNER)
+    }
+
+    #[cfg_attr(feature = "leak-tracker", track_caller)]
+    fn alloc_owned_memory(&mut self, size : usize, owner : OwnerId)->Option<*mut u8> {
+        self.alloc_record(size, ZoneId::USER, Tag::NONE, owner)
+    }
+
+    fn remove_owned_pools(&mut self, owner : OwnerId) {
+        assert!(owner != NO_OWNER);
+        for zone in 0..ZONE_NUM {
+            let mut prev : Option<*mut MemoryPool> = None;
+            let mut head = self.allocator[zone];
+            unsafe {
+                while head.is_some() {
+                    let node = &mut *head.unwrap();
+                    if node.kind.owner == owner {
+                        #[cfg(feature = "leak-tracker")]
+                        self.tracker.remove_range(node.physic_base, node.end());
+                        if let Some(prev) = prev {
+                            (*prev).next = node.next;
+                        }
+                        else {
+                            self.allocator[zone] = node.next;
+                        }
+                    }
+                    else {
+                        prev = head;
+                    }
+                    head = node.next;
+                }
+            }
+        }
+    }
+
+    fn owned_memory_size(&self, owner : OwnerId, zone : ZoneId)->usize {
+        self.used_size_of(zone.val()..zone.val() + 1, |t| t.kind.owner == owner)
+    }
+
+    fn block_size(&self, size : usize, zone : ZoneId, owner : OwnerId)->usize {
+        self.block_size_of(size, zone, PoolKind::new(Tag::NONE, owner, false))
+    }
+
+    fn movable_size(&self, size : usize, zone : ZoneId)->usize {
+        self.block_size_of(size + MOVABLE_HEADER, zone, PoolKind::MOVABLE) +
+            self.block_size_of(size_of::<usize>(), zone, PoolKind::NONE)
+    }
+
+    fn pool_page_need(&self, size : usize, zone : ZoneId, owner : OwnerId)->usize {
+        self.page_need_of(size, zone, PoolKind::new(Tag::NONE, owner, false))
+    }
+
+    fn movable_page_need(&self, size : usize, zone : ZoneId)->usize {
+        self.page_need_of(size + MOVABLE_HEADER, zone, PoolKind::MOVABLE) +
+            self.page_need_of(size_of::<usize>(), zone, PoolKind::NONE)
+    }
+
+    fn block_of(&self, addr : *mut u8, zone : ZoneId)->(OwnerId, usize) {
+        let mut head = self.allocator[zone.val()];
+        unsafe {
+            while !(*head.unwrap()).is_contain(addr) {
+                head = (*head.unwrap()).next;
+            }
+            let node = &*head.unwrap();
+            (node.kind.owner, node.size)
+        }
+    }
+
+    fn tag_memory_size(&self, tag : Tag)->usize {
+        self.used_size_of(0..ZONE_NUM, |t| t.kind.tag == tag)
+    }
+
+    fn free_memory(&mut self, addr : *mut u8, zone : ZoneId) {
+        self.free_record(addr, zone);
+    }
+
+    fn alloc_movable(&mut self, size : usize, zone : ZoneId)->Option<MovableHandle> {
+        let slot = self.alloc(size_of::<usize>(), zone, PoolKind::NONE)?;
+        let block = match self.alloc(size + MOVABLE_HEADER, zone, PoolKind::MOVABLE) {
+            Some(block) => block,
+            None => {
+                self.free(slot, zone);
+                return None;
+            }
+        };
+        let slot = slot as *mut *mut u8;
+        unsafe {
+            *(block as *mut *mut *mut u8) = slot;
+            *slot = block.add(MOVABLE_HEADER);
+        }
+        Some(MovableHandle::new(slot))
     }
 
-    fn free_kernel_memory(&mut self, addr : *mut u8) {
-        self.free(addr, true);
+    fn free_movable(&mut self, handle : MovableHandle, zone : ZoneId) {
+        let block = unsafe {handle.addr().sub(MOVABLE_HEADER)};
+        self.free(block, zone);
+        self.free(handle.slot() as *mut u8, zone);
     }
 
-    fn free_user_memory(&mut self, addr : *mut u8) {
-        self.free(addr, false);
+    fn compact(&mut self, zone : ZoneId)->usize {
+        let page_size = self.page_manager.page_size();
+        let mut cnt = 0;
+        // 每次搬空一个内存池后重新查找，释放可能改变链表
+        while let Some(pool) = self.find_sparse(zone) {
+            self.evacuate(pool, zone);
+            let num = unsafe {(*pool).page_num(page_size)};
+            if self.release_pool(pool, zone) {
+                cnt += num;
+            }
+        }
+        cnt
+    }
+
+    #[cfg(feature = "leak-tracker")]
+    fn tracker(&self)->&LeakTracker {
+        &self.tracker
+    }
+
+    fn pool_page_num(&self, size : usize)->usize {
+        let size = align(size);
+        let num = self.decide_page_num(size);
+        let total_size = num * self.page_manager.page_size();
+        let struct_size = (total_size / size).div_ceil(8) + size_of::<MemoryPool>();
+        // 结构体另外存放时，可能还要为它新建一个内存池
+        if self.is_struct_outside(size, struct_size) {
+            num + self.decide_page_num(align(struct_size))
+        }
+        else {
+            num
+        }
+    }
+
+    fn set_policy(&mut self, policy : HeapPolicy) {
+        self.policy = policy;
     }
 
     fn print(&self) {
-        let mut head = self.kernel_allocator;
+        let mut head = self.allocator[ZoneId::KERNEL.val()];
 
         unsafe {
             while head.is_some() {
@@ -256,6 +532,26 @@ pub struct MemoryPool {
     size : usize,
     next : Option<*mut MemoryPool>,
     bitmap : Bitmap,
+    kind : PoolKind,
+}
+
+/// ## 内存池的类别
+/// 标签、所有者及是否可移动都相同的内存池才能共用
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct PoolKind {
+    tag : Tag,
+    owner : OwnerId,
+    /// 只存放可移动块，块开头为句柄槽位的地址
+    movable : bool,
+}
+
+impl PoolKind {
+    const NONE : Self = Self::new(Tag::NONE, NO_OWNER, false);
+    const MOVABLE : Self = Self::new(Tag::NONE, NO_OWNER, true);
+
+    const fn new(tag : Tag, owner : OwnerId, movable : bool)->Self {
+        Self { tag, owner, movable }
+    }
 }
 
 /// 将某个数向上取 2^n
@@ -269,12 +565,12 @@ fn align(x : usize) -> usize{
 
 /// ## 私有辅助方法
 impl MemoryPool {
-    pub fn can_contain(&mut self, size : usize)->bool {
-        self.bitmap.free_cnt > 0 && self.size >= size
+    fn can_contain(&mut self, size : usize, kind : PoolKind)->bool {
+        self.bitmap.free_cnt > 0 && self.size >= size && self.kind == kind
     }
     /// ### 初始化变量
     fn init(&mut self, addr : *mut u8, total_size : usize, sz : usize,
-        bit_addr : *mut u8, free_cnt : usize) {
+        bit_addr : *mut u8, free_cnt : usize, kind : PoolKind) {
         let size = align(sz);
         self.physic_base = addr;
         let total_cnt = total_size / size;
@@ -282,10 +578,52 @@ impl MemoryPool {
         self.size = size;
         // self.bitlen = self.total_cnt / 8;
         self.next = None;
+        self.kind = kind;
+    }
+
+    /// ### 正在使用的字节数
+    fn used_size(&self)->usize {
+        self.live_cnt() * self.size
+    }
+
+    /// ### 内存池结构体放在自身页面内时占用的块数
+    fn meta_cnt(&self)->usize {
+        if self.is_struct_inside() {
+            self.struct_size().div_ceil(self.size)
+        }
+        else {
+            0
+        }
+    }
+
+    /// ### 正在使用的块数，不含结构体占用的块
+    fn live_cnt(&self)->usize {
+        self.bitmap.total_cnt - self.bitmap.free_cnt - self.meta_cnt()
+    }
+
+    /// ### 使用的块不超过可用块的 1 / SPARSE_RATIO
+    fn is_sparse(&self)->bool {
+        self.live_cnt() * SPARSE_RATIO <= self.bitmap.total_cnt - self.meta_cnt()
+    }
+
+    /// ### 是否可以接收 other 中的块
+    /// 按使用的块数、地址排出先后，只向更满的内存池搬，避免来回搬动
+    fn is_denser(&self, other : *mut MemoryPool)->bool {
+        let other_ref = unsafe {&*other};
+        self.kind.movable && self.size == other_ref.size && self.kind == other_ref.kind &&
+            (self.live_cnt(), self as *const Self as usize) > (other_ref.live_cnt(), other as usize)
+    }
+
+    fn page_num(&self, page_size : usize)->usize {
+        self.bitmap.total_cnt * self.size / page_size
+    }
+
+    fn struct_size(&self)->usize {
+        self.bitmap.total_cnt.div_ceil(8) + size_of::<MemoryPool>()
     }
 
-    fn is_inside(&self)->bool {
-        self.size < MEMORY_SIZE_INSIDE
+    fn is_struct_inside(&self)->bool {
+        self as *const Self as *mut u8 == self.physic_base
     }
     /// ### 根据地址找到对应的元素然后释放
     fn free_bitmap(&mut self, addr : *mut u8){
@@ -297,15 +635,75 @@ impl MemoryPool {
     fn is_contain(&self, addr : *mut u8) -> bool {
         let adr = addr as usize;
         let st = self.physic_base as usize;
-        let ed = st + self.bitmap.total_cnt * self.size;
-        adr >= st && adr < ed
+        adr >= st && adr < self.end() as usize
+    }
+
+    fn end(&self)->*mut u8 {
+        (self.physic_base as usize + self.bitmap.total_cnt * self.size) as *mut u8
     }
 }
 
 const MEMORY_TOO_BIG : usize = 4096;
 const MEMORY_SIZE_INSIDE : usize = 256;
-
-
-use core::{mem::size_of};
-
-use crate::{bitmap::Bitmap, require::{HeapOp, PageOp}};
+/// 已释放内存的填充值
+const POISON : u8 = 0xa5;
+/// 整理时视为稀疏的使用比例
+const SPARSE_RATIO : usize = 4;
+
+
+use core::{mem::size_of, ops::Range};
+#[cfg(feature = "leak-tracker")]
+use core::panic::Location;
+
+use crate::{bitmap::Bitmap, config::ZONE_NUM, movable::{MOVABLE_HEADER, MovableHandle}, page::PageBit,
+    require::{HeapOp, NO_OWNER, OwnerId, PageOp}, tag::Tag, zone::ZoneId};
+#[cfg(feature = "leak-tracker")]
+use crate::leak::LeakTracker;
+
+
+#[cfg(test)]
+mod test {
+    use crate::{MemoryOp, config::PAGE_SIZE, testing::Arena, zone::ZoneId};
+
+    #[test]
+    fn compact_pinned() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        let mut keep = Vec::new();
+        for i in 0..200usize {
+            let handle = mgr.alloc_movable(100, ZoneId::KERNEL).unwrap();
+            unsafe {
+                *(handle.addr() as *mut usize) = i;
+            }
+            keep.push((i, handle));
+        }
+        // 前面的内存池保持较满，其余的变得稀疏
+        keep.retain(|(i, handle)| {
+            let rt = *i < 30 || i % 20 == 0;
+            if !rt {
+                mgr.free_movable(*handle);
+            }
+            rt
+        });
+        // 固定其中一个内存池的页面，整理时不能搬走
+        let pinned = (keep[33].1.addr() as usize / PAGE_SIZE * PAGE_SIZE) as *mut u8;
+        let addr = keep[33].1.addr();
+        assert!(mgr.pin_pages(pinned, 1));
+        let before = mgr.free_page_num(ZoneId::KERNEL);
+        let cnt = mgr.compact();
+        assert!(cnt > 0);
+        assert_eq!(mgr.free_page_num(ZoneId::KERNEL), before + cnt);
+        assert_eq!(keep[33].1.addr(), addr);
+        assert_eq!(mgr.pinned_page_num(ZoneId::KERNEL), 1);
+        for (i, handle) in keep.iter() {
+            assert_eq!(unsafe {*(handle.addr() as *const usize)}, *i);
+        }
+        // 解除固定后可以继续整理
+        mgr.unpin_pages(pinned, 1);
+        mgr.compact();
+        for (i, handle) in keep {
+            assert_eq!(unsafe {*(handle.addr() as *const usize)}, i);
+            mgr.free_movable(handle);
+        }
+    }
+}
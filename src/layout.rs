@@ -0,0 +1,226 @@
+//! # 内存布局
+//! 记录交给内存管理器的物理内存范围，以及其中固件、设备树等不可使用的保留范围
+//! 可以从设备树或链接脚本的符号读取，再通过 MemoryManager::from_layout 创建内存管理器
+//! 由链接符号得到的各个段同时告诉页表映射时使用的权限
+//!
+//! 2021年5月2日 zg
+
+use core::ops::Range;
+
+use crate::{config::{BSS_END, BSS_START, DATA_END, DATA_START, HEAP_SIZE, HEAP_START,
+    KERNEL_STACK_END, KERNEL_STACK_START, MEMORY_END, MEMORY_START, RESERVED_RANGE_NUM,
+    RODATA_END, RODATA_START, TEXT_END, TEXT_START}, error::MemoryError, fdt::{Fdt, Region}};
+
+/// ## 映射权限
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Permission(pub u8);
+
+impl Permission {
+    pub const READ : Self = Self(1 << 0);
+    pub const WRITE : Self = Self(1 << 1);
+    pub const EXECUTE : Self = Self(1 << 2);
+    pub const READ_WRITE : Self = Self(Self::READ.0 | Self::WRITE.0);
+    pub const READ_EXECUTE : Self = Self(Self::READ.0 | Self::EXECUTE.0);
+
+    pub const fn contains(self, other : Self)->bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SectionKind {
+    Text,
+    Rodata,
+    Data,
+    Bss,
+    KernelStack,
+    Heap,
+}
+
+/// ## 内核镜像中的段
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Section {
+    pub kind : SectionKind,
+    pub range : Range<usize>,
+    pub perm : Permission,
+}
+
+impl Section {
+    pub const fn new(kind : SectionKind, range : Range<usize>, perm : Permission)->Self {
+        Self { kind, range, perm }
+    }
+}
+
+const SECTION_NUM : usize = 6;
+
+pub struct MemoryLayout {
+    /// 管理范围的起点，一般为内核镜像之后
+    pub heap_start : usize,
+    /// 物理内存的终点
+    pub memory_end : usize,
+    reserved : [Range<usize>; RESERVED_RANGE_NUM],
+    reserved_num : usize,
+    sections : [Section; SECTION_NUM],
+    section_num : usize,
+}
+
+impl MemoryLayout {
+    const EMPTY : Range<usize> = 0..0;
+    const NO_SECTION : Section = Section::new(SectionKind::Text, 0..0, Permission(0));
+
+    pub const fn new(heap_start : usize, memory_end : usize)->Self {
+        Self {
+            heap_start,
+            memory_end,
+            reserved : [Self::EMPTY; RESERVED_RANGE_NUM],
+            reserved_num : 0,
+            sections : [Self::NO_SECTION; SECTION_NUM],
+            section_num : 0,
+        }
+    }
+
+    /// ## 从链接脚本的符号读取
+    /// 堆段之后直到 MEMORY_END 的内存都交给内存管理器
+    pub fn from_linker()->Result<Self, MemoryError> {
+        let sections = unsafe {[
+            Section::new(SectionKind::Text, TEXT_START..TEXT_END, Permission::READ_EXECUTE),
+            Section::new(SectionKind::Rodata, RODATA_START..RODATA_END, Permission::READ),
+            Section::new(SectionKind::Data, DATA_START..DATA_END, Permission::READ_WRITE),
+            Section::new(SectionKind::Bss, BSS_START..BSS_END, Permission::READ_WRITE),
+            Section::new(SectionKind::KernelStack,
+                KERNEL_STACK_START..KERNEL_STACK_END, Permission::READ_WRITE),
+            Section::new(SectionKind::Heap,
+                HEAP_START..HEAP_START + HEAP_SIZE, Permission::READ_WRITE),
+        ]};
+        unsafe {Self::with_sections(&sections, MEMORY_START..MEMORY_END)}
+    }
+
+    /// ## 由各个段组成
+    /// 段须按地址从低到高排列、互不重叠且位于 memory 之内，最后一段为堆
+    /// 不满足时返回 InvalidLayout
+    pub fn with_sections(sections : &[Section], memory : Range<usize>)->Result<Self, MemoryError> {
+        if sections.is_empty() || sections.len() > SECTION_NUM {
+            return Err(MemoryError::InvalidLayout);
+        }
+        let mut end = memory.start;
+        for section in sections {
+            if section.range.start < end || section.range.start > section.range.end {
+                return Err(MemoryError::InvalidLayout);
+            }
+            end = section.range.end;
+        }
+        let heap = &sections[sections.len() - 1];
+        if end > memory.end || heap.kind != SectionKind::Heap {
+            return Err(MemoryError::InvalidLayout);
+        }
+        let mut rt = Self::new(heap.range.start, memory.end);
+        for (i, section) in sections.iter().enumerate() {
+            rt.sections[i] = section.clone();
+        }
+        rt.section_num = sections.len();
+        Ok(rt)
+    }
+
+    /// ## 从设备树读取
+    /// fdt 为设备树所在的物理地址，heap_start 须位于某个 /memory 范围内，只管理这一段内存
+    /// 内存保留块、/reserved-memory 的子节点以及设备树自身都记为保留范围
+    pub fn from_fdt(fdt : usize, heap_start : usize)->Result<Self, MemoryError> {
+        let fdt = Fdt::new(fdt)?;
+        let mut memory_end = None;
+        fdt.for_each_region(&mut |region, range| {
+            if region == Region::Memory && range.contains(&heap_start) {
+                memory_end = Some(range.end);
+            }
+        })?;
+        let mut rt = Self::new(heap_start, memory_end.ok_or(MemoryError::InvalidDeviceTree)?);
+        let mut result = Ok(());
+        fdt.for_each_region(&mut |region, range| {
+            if region == Region::Reserved && result.is_ok() {
+                result = rt.add_reserved(range);
+            }
+        })?;
+        result.map(|_| rt)
+    }
+
+    /// ### 添加保留范围
+    /// 管理范围之外的部分会被忽略，保留范围表满时返回 ReservedTableFull
+    pub fn add_reserved(&mut self, range : Range<usize>)->Result<(), MemoryError> {
+        if range.end <= self.heap_start || range.start >= self.memory_end {
+            return Ok(());
+        }
+        if self.reserved_num == RESERVED_RANGE_NUM {
+            return Err(MemoryError::ReservedTableFull);
+        }
+        self.reserved[self.reserved_num] = range;
+        self.reserved_num += 1;
+        Ok(())
+    }
+
+    pub fn reserved(&self)->&[Range<usize>] {
+        &self.reserved[..self.reserved_num]
+    }
+
+    /// ### 内核镜像各段及其映射权限
+    /// 从设备树读取的布局没有段信息
+    pub fn sections(&self)->&[Section] {
+        &self.sections[..self.section_num]
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{MemoryOp, config::PAGE_SIZE, error::MemoryError, testing::{Arena, Manager}, zone::ZoneId};
+    use super::{MemoryLayout, Permission, Section, SectionKind};
+
+    fn sections(base : usize)->[Section; 6] {
+        let page = |i : usize| base + i * PAGE_SIZE;
+        [
+            Section::new(SectionKind::Text, page(0)..page(4), Permission::READ_EXECUTE),
+            Section::new(SectionKind::Rodata, page(4)..page(6), Permission::READ),
+            Section::new(SectionKind::Data, page(6)..page(7), Permission::READ_WRITE),
+            Section::new(SectionKind::Bss, page(7)..page(7), Permission::READ_WRITE),
+            Section::new(SectionKind::KernelStack, page(8)..page(10), Permission::READ_WRITE),
+            Section::new(SectionKind::Heap, page(10)..page(12), Permission::READ_WRITE),
+        ]
+    }
+
+    #[test]
+    fn with_sections() {
+        let arena = Arena::new(1 << 20);
+        let sec = sections(arena.start());
+        let layout = MemoryLayout::with_sections(&sec, arena.start()..arena.end()).unwrap();
+        assert_eq!(layout.heap_start, arena.start() + 10 * PAGE_SIZE);
+        assert_eq!(layout.memory_end, arena.end());
+        assert_eq!(layout.sections(), sec);
+        assert!(layout.reserved().is_empty());
+        let text = &layout.sections()[0];
+        assert!(text.perm.contains(Permission::EXECUTE) && !text.perm.contains(Permission::WRITE));
+        // 堆段之后的内存交给内存管理器
+        let mut mgr = Manager::from_layout(&layout, PAGE_SIZE).unwrap();
+        let addr = mgr.kernel_page(1).unwrap() as usize;
+        assert!(addr >= layout.heap_start);
+        assert_eq!(mgr.free_page_num(ZoneId::DMA), ((1 << 20) / PAGE_SIZE - 10) / 8);
+    }
+
+    #[test]
+    fn invalid() {
+        let arena = Arena::new(1 << 20);
+        let memory = arena.start()..arena.end();
+        let mut sec = sections(arena.start());
+        assert_eq!(MemoryLayout::with_sections(&[], memory.clone()).err(), Some(MemoryError::InvalidLayout));
+        // 最后一段不是堆
+        assert_eq!(MemoryLayout::with_sections(&sec[..5], memory.clone()).err(), Some(MemoryError::InvalidLayout));
+        // 超出物理内存
+        assert_eq!(MemoryLayout::with_sections(&sec, memory.start..memory.start + 11 * PAGE_SIZE).err(),
+            Some(MemoryError::InvalidLayout));
+        assert_eq!(MemoryLayout::with_sections(&sec, memory.start + PAGE_SIZE..memory.end).err(),
+            Some(MemoryError::InvalidLayout));
+        // 重叠
+        sec[2].range.start -= PAGE_SIZE;
+        assert_eq!(MemoryLayout::with_sections(&sec, memory.clone()).err(), Some(MemoryError::InvalidLayout));
+        // 起点在终点之后
+        sec[2].range = sec[2].range.end..sec[2].range.start;
+        assert_eq!(MemoryLayout::with_sections(&sec, memory).err(), Some(MemoryError::InvalidLayout));
+    }
+}
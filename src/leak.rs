@@ -0,0 +1,189 @@
+//! # 泄漏追踪
+//! 记录每一块仍在使用的堆内存的大小、标签以及申请位置
+//! 通过 mark 记下检查点，之后用 since 列出检查点之后申请且未释放的内存
+//! 仅在开启 leak-tracker 特性时编译
+//!
+//! 2021年4月21日 zg
+
+use core::{mem::size_of, panic::Location, ptr::{copy_nonoverlapping, null_mut}, slice::from_raw_parts};
+
+use crate::{require::PageOp, tag::Tag, zone::ZoneId};
+
+/// ## 申请记录
+#[derive(Copy, Clone)]
+pub struct AllocRecord {
+    pub addr : usize,
+    /// 对齐后的块大小
+    pub size : usize,
+    pub tag : Tag,
+    /// 申请时的调用位置
+    pub location : &'static Location<'static>,
+    /// 申请序号，越大越晚
+    pub seq : usize,
+}
+
+/// ## 检查点
+#[derive(Copy, Clone)]
+pub struct LeakMark(usize);
+
+pub struct LeakTracker {
+    records : *mut AllocRecord,
+    capacity : usize,
+    len : usize,
+    page_num : usize,
+    seq : usize,
+    /// 记录表扩容失败而丢弃的记录数
+    pub dropped : usize,
+}
+
+impl Default for LeakTracker {
+    fn default()->Self {
+        Self::new()
+    }
+}
+
+impl LeakTracker {
+    pub const fn new()->Self {
+        Self {
+            records : null_mut(),
+            capacity : 0,
+            len : 0,
+            page_num : 0,
+            seq : 0,
+            dropped : 0,
+        }
+    }
+
+    pub fn insert<T:PageOp>(&mut self, page : &mut T, addr : *mut u8, size : usize,
+            tag : Tag, location : &'static Location<'static>) {
+        if self.len == self.capacity && !self.grow(page) {
+            self.dropped += 1;
+            return;
+        }
+        unsafe {
+            self.records.add(self.len).write(AllocRecord {
+                addr : addr as usize,
+                size,
+                tag,
+                location,
+                seq : self.seq,
+            });
+        }
+        self.len += 1;
+        self.seq += 1;
+    }
+
+    pub fn remove(&mut self, addr : *mut u8) {
+        for i in 0..self.len {
+            unsafe {
+                if (*self.records.add(i)).addr == addr as usize {
+                    self.len -= 1;
+                    *self.records.add(i) = *self.records.add(self.len);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// ### 移除 [start, end) 范围内的所有记录
+    pub fn remove_range(&mut self, start : *mut u8, end : *mut u8) {
+        let mut i = 0;
+        while i < self.len {
+            unsafe {
+                let addr = (*self.records.add(i)).addr;
+                if addr >= start as usize && addr < end as usize {
+                    self.len -= 1;
+                    *self.records.add(i) = *self.records.add(self.len);
+                    continue;
+                }
+            }
+            i += 1;
+        }
+    }
+
+    /// ### 所有未释放的内存
+    pub fn iter(&self)->impl Iterator<Item=&AllocRecord> {
+        self.records().iter()
+    }
+
+    pub fn mark(&self)->LeakMark {
+        LeakMark(self.seq)
+    }
+
+    /// ### 检查点之后申请且仍未释放的内存
+    pub fn since(&self, mark : LeakMark)->impl Iterator<Item=&AllocRecord> {
+        self.records().iter().filter(move |r| r.seq >= mark.0)
+    }
+
+    fn records(&self)->&[AllocRecord] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe {from_raw_parts(self.records, self.len)}
+    }
+
+    /// ### 记录表扩容
+    /// 记录表直接使用内核页面，避免递归申请堆内存
+    fn grow<T:PageOp>(&mut self, page : &mut T)->bool {
+        let page_num = if self.page_num == 0 { 1 } else { self.page_num * 2 };
+        let addr = match page.alloc_page(page_num, ZoneId::KERNEL) {
+            Some(addr) => addr as *mut AllocRecord,
+            None => return false,
+        };
+        if self.page_num != 0 {
+            unsafe {
+                copy_nonoverlapping(self.records, addr, self.len);
+            }
+            page.free_page(self.records as *mut u8);
+        }
+        self.records = addr;
+        self.page_num = page_num;
+        self.capacity = page_num * page.page_size() / size_of::<AllocRecord>();
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec::Vec;
+
+    use crate::{MemoryOp, tag::Tag, testing::Arena, zone::ZoneId};
+
+    #[test]
+    fn since_mark() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        let early = mgr.alloc_memory(10, ZoneId::KERNEL).unwrap();
+        let mark = mgr.tracker().mark();
+        let line = line!() + 1;
+        let leaked = mgr.alloc_memory(64, ZoneId::USER).unwrap();
+        let tagged : Vec<_> = (0..10).map(|i| mgr.alloc_tagged(8 + i, Tag::FS).unwrap()).collect();
+        assert_eq!(mgr.tracker().iter().count(), 12);
+        for addr in tagged {
+            mgr.free_memory(addr);
+        }
+        let since : Vec<_> = mgr.tracker().since(mark).collect();
+        assert_eq!(since.len(), 1);
+        assert_eq!((since[0].addr, since[0].size), (leaked as usize, 64));
+        assert_eq!((since[0].location.file(), since[0].location.line()), (file!(), line));
+        mgr.free_memory(early);
+        mgr.free_memory(leaked);
+        assert_eq!(mgr.tracker().iter().count(), 0);
+    }
+
+    #[test]
+    fn grow_from_user_zone() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        let kernel = mgr.free_page_num(ZoneId::KERNEL);
+        // 记录表扩容使用内核页面，即使申请的是用户区域的内存
+        let addr : Vec<_> = (0..300).map(|_| mgr.alloc_memory(16, ZoneId::USER).unwrap()).collect();
+        assert_eq!(mgr.tracker().iter().count(), 300);
+        assert_eq!(mgr.tracker().dropped, 0);
+        assert!(mgr.free_page_num(ZoneId::KERNEL) < kernel);
+        for addr in addr {
+            mgr.free_memory(addr);
+        }
+        assert_eq!(mgr.tracker().iter().count(), 0);
+    }
+}
@@ -5,7 +5,7 @@
 //! 
 //! 2021年4月14日 zg
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 mod require;
 mod page;
@@ -13,14 +13,46 @@ mod heap;
 mod bitmap;
 mod config;
 mod manager;
+mod tag;
+mod error;
+mod quota;
+mod reclaim;
+mod watermark;
+mod reserve;
+mod zone;
+mod dma;
+mod fdt;
+mod layout;
+mod builder;
+mod movable;
+mod migrate;
+#[cfg(feature = "leak-tracker")]
+mod leak;
+#[cfg(test)]
+mod testing;
 
 pub use require::{
     PageOp,
     HeapOp,
     MemoryOp,
     AutoMemory,
+    OwnerId,
+    NO_OWNER,
 };
 
-pub use heap::Heap;
-pub use page::PageManager;
+pub use tag::{Tag, TagUsage};
+pub use error::{ConfigError, MemoryError};
+pub use quota::Quota;
+pub use reclaim::{OomHandler, Shrinker};
+pub use watermark::{LowMemoryHandler, Watermark};
+pub use zone::ZoneId;
+pub use dma::DmaBuffer;
+pub use movable::MovableHandle;
+pub use migrate::Relocate;
+pub use layout::{MemoryLayout, Permission, Section, SectionKind};
+pub use builder::{KernelShare, MemoryConfig};
+pub use heap::{Heap, HeapPolicy};
+pub use page::{PageBit, PageInfo, PageManager};
 pub use manager::MemoryManager;
+#[cfg(feature = "leak-tracker")]
+pub use leak::{AllocRecord, LeakMark, LeakTracker};
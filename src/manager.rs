@@ -1,113 +1,1232 @@
 //! # 内存管理器
 //! 处理内存请求（页面、堆内存），这是此库提供的默认实现实现
 //! ## 使用示例
-//! ```rust
-//! let mgr = MemoryManager::new(0, 128, 4096, 0x100000);
-//! let addr = mgr.alloc_memory(4, true);
-//! *addr = 9;
-//! mgr.free_kernel_memory(addr);
+//! 管理范围须是真实可用的物理内存，这里只检查编译
+//! ```rust,no_run
+//! use tisu_memory::{Heap, MemoryManager, MemoryOp, PageManager, ZoneId};
+//!
+//! let mut mgr : MemoryManager<PageManager, Heap<PageManager>> =
+//!     MemoryManager::new(0x8040_0000, 128, 4096, 0x8800_0000);
+//! let addr = mgr.alloc_memory(4, ZoneId::KERNEL).unwrap();
+//! unsafe { *addr = 9; }
+//! mgr.free_memory(addr);
 //! ```
 //! 
 //! 2021年4月14日 zg
 
+use core::{array::from_fn, ops::Range};
+
 use tisu_sync::SpinMutex;
-use crate::{MemoryOp, require::{HeapOp, PageOp}};
+use crate::{MemoryOp, builder::MemoryConfig, config::{FREE_RUN_ORDER, KERNEL_PAGE_NUM, MIGRATE_NUM, ZONE_NUM},
+    error::{ConfigError, MemoryError}, layout::MemoryLayout, migrate::{MigrateTable, Movable, Relocate}, movable::MovableHandle, page::{PageBit, PageInfo, huge_page_num, is_valid_align}, quota::{Quota, QuotaTable, Usage},
+    reclaim::{OomHandler, Reclaim, Shrinker}, reserve::Reserve,
+    require::{HeapOp, NO_OWNER, OwnerId, PageOp}, tag::{Tag, TagUsage},
+    watermark::{LowMemoryHandler, Watermark}, zone::{ZoneId, default_dma_page_num}};
+#[cfg(feature = "leak-tracker")]
+use crate::leak::LeakTracker;
 
 pub struct MemoryManager<T1 : PageOp, T2 : HeapOp<T1>> {
-    kernel_start : *mut u8,
-    user_start : *mut u8,
     page : T1,
     memory : T2,
-    kernel_mutex : SpinMutex,
-    user_mutex : SpinMutex,
+    /// 每个区域一把锁，需要同时持有多把时按区域编号从小到大加锁
+    mutex : [SpinMutex; ZONE_NUM],
+    quota : QuotaTable,
+    reclaim : Reclaim,
+    reserve : Reserve,
+    migrate : MigrateTable,
+    /// 可移动页面登记表的锁，需要同时持有区域的锁时最后加锁
+    migrate_mutex : SpinMutex,
+}
+
+/// ## 连续页面的申请要求
+/// 申请失败后迁移页面时按此寻找窗口
+#[derive(Copy, Clone)]
+struct PageRequest {
+    num : usize,
+    /// 起始地址对齐的字节数
+    align : usize,
+    /// 最后一个字节的地址上限
+    max_addr : usize,
+}
+
+impl PageRequest {
+    const fn new(num : usize)->Self {
+        Self { num, align : 1, max_addr : usize::MAX }
+    }
+
+    const fn aligned(num : usize, align : usize, max_addr : usize)->Self {
+        Self { num, align, max_addr }
+    }
+
+    /// 页面数不为 0，对齐为 2 的幂或页面大小的整数倍
+    fn is_valid(&self, page_size : usize)->bool {
+        self.num > 0 && is_valid_align(self.align, page_size)
+    }
+
+    /// 没有地址限制
+    const fn is_plain(&self)->bool {
+        self.align == 1 && self.max_addr == usize::MAX
+    }
 }
 
 impl<T1 : PageOp, T2 : HeapOp<T1>> MemoryManager<T1, T2> {
+    /// ## 创建
+    /// 不划分 DMA 区域，heap_start 起 kernel_page_num 个页面归内核区域，其余归用户区域
+    /// 需要 DMA 区域时使用 with_zones、from_layout 或 from_config
     pub fn new(
         heap_start : usize,
         kernel_page_num : usize,
         page_size : usize,
         memory_end : usize
     )->Self {
-        let user_heap = heap_start + kernel_page_num * page_size;
+        Self::with_zones(heap_start, &[0, kernel_page_num], &[], page_size, memory_end)
+    }
+
+    /// ## 按内存布局创建
+    /// 最低处的页面归 DMA 区域，大小见 default_dma_page_num，
+    /// 内核区域取 KERNEL_PAGE_NUM 个页面，但不超过可用内存的一半，之后边界会按需移动
+    /// 内存过小、放不下页面数组，或保留范围与页面数组重叠时返回 InvalidLayout
+    pub fn from_layout(layout : &MemoryLayout, page_size : usize)->Result<Self, MemoryError> {
+        let start = layout.heap_start.div_ceil(page_size) * page_size;
+        let total_num = layout.memory_end.saturating_sub(start) / page_size;
+        let zones = [default_dma_page_num(total_num), KERNEL_PAGE_NUM.min(total_num / 2)];
+        let kernel = ZoneId::KERNEL.val();
+        // 页面数组在内核区域开头，大小按实际划分的区域数计算，剩余内存另成一个区域
+        let meta_num = T1::meta_size(total_num, zones.len() + 1).div_ceil(page_size);
+        let meta_start = start + zones[..kernel].iter().sum::<usize>() * page_size;
+        let meta_end = meta_start + meta_num * page_size;
+        if zones[kernel] <= meta_num || layout.reserved().iter()
+                .any(|r| r.start < meta_end && r.end > meta_start) {
+            return Err(MemoryError::InvalidLayout);
+        }
+        Ok(Self::with_zones(layout.heap_start, &zones, layout.reserved(), page_size, layout.memory_end))
+    }
+
+    /// ## 按配置创建
+    /// 配置不合理时返回错误，见 MemoryConfig::validate
+    pub fn from_config(config : &MemoryConfig)->Result<Self, ConfigError> {
+        let zones = [config.dma_page_num(), config.kernel_page_num()];
+        config.validate(|page_num| T1::meta_size(page_num, zones.len() + 1))?;
+        let layout = &config.layout;
+        let mut rt = Self::with_zones(layout.heap_start, &zones,
+            layout.reserved(), config.page_size, layout.memory_end);
+        rt.memory.set_policy(config.heap);
+        Ok(rt)
+    }
+
+    /// ## 划分多个区域
+    /// zone_page_num 依次为 0 号区域（DMA）起各区域的页面数，剩余内存全部归最后一个区域
+    /// 至少需要给出 DMA 区域的大小，内核区域的开头存放页面数组
+    /// reserved 为固件、设备树、MMIO 等不可使用的物理内存范围，不能与页面数组重叠，
+    /// 需要检查时使用 from_layout 或 from_config
+    pub fn with_zones(
+        heap_start : usize,
+        zone_page_num : &[usize],
+        reserved : &[Range<usize>],
+        page_size : usize,
+        memory_end : usize
+    )->Self {
+        assert!(!zone_page_num.is_empty(), "DMA zone size missing");
+        assert!(zone_page_num.len() < ZONE_NUM, "too many zones: {}", zone_page_num.len() + 1);
+        let mut zone_start = [0; ZONE_NUM];
+        let mut start = heap_start;
+        for (i, num) in zone_page_num.iter().enumerate() {
+            start += num * page_size;
+            zone_start[i] = start;
+        }
         let page = T1::new(heap_start,
-            user_heap, memory_end, page_size);
+            &zone_start[..zone_page_num.len()], reserved, memory_end, page_size);
         let p = page.clone();
-        Self {
-            kernel_start : heap_start as *mut u8,
-            user_start : user_heap as *mut u8,
+        let mut rt = Self {
             page,
             memory: T2::new(p),
-            kernel_mutex : SpinMutex::new(),
-            user_mutex : SpinMutex::new(),
+            mutex : from_fn(|_| SpinMutex::new()),
+            quota : QuotaTable::new(),
+            reclaim : Reclaim::new(),
+            reserve : Reserve::new(),
+            migrate : MigrateTable::new(),
+            migrate_mutex : SpinMutex::new(),
+        };
+        rt.reserve.refill(&mut rt.page);
+        rt
+    }
+
+    /// ### 内核内存释放后补齐保留页面，需持有内核锁
+    fn refill_reserve(&mut self) {
+        if !self.reserve.is_full() {
+            self.reserve.refill(&mut self.page);
+        }
+    }
+
+    fn lock(&mut self, zone : ZoneId) {
+        self.mutex[zone.val()].lock_no_int();
+    }
+
+    fn unlock(&mut self, zone : ZoneId) {
+        self.mutex[zone.val()].unlock_no_int();
+    }
+
+    /// ### 堆内存操作加锁
+    /// 泄漏追踪的记录表由各区域共用，扩容时申请内核页面，
+    /// 开启 leak-tracker 时其它区域的堆内存操作同时持有内核锁
+    fn lock_heap(&mut self, zone : ZoneId) {
+        #[cfg(feature = "leak-tracker")]
+        if zone != ZoneId::KERNEL {
+            let (low, high) = if zone.val() < ZoneId::KERNEL.val() {
+                (zone, ZoneId::KERNEL)
+            }
+            else {
+                (ZoneId::KERNEL, zone)
+            };
+            self.lock(low);
+            self.lock(high);
+            return;
+        }
+        self.lock(zone);
+    }
+
+    fn unlock_heap(&mut self, zone : ZoneId) {
+        #[cfg(feature = "leak-tracker")]
+        if zone != ZoneId::KERNEL {
+            self.unlock(ZoneId::KERNEL);
+        }
+        self.unlock(zone);
+    }
+
+    fn lock_all(&mut self) {
+        for i in 0..self.page.zone_num() {
+            self.mutex[i].lock_no_int();
+        }
+    }
+
+    fn unlock_all(&mut self) {
+        for i in (0..self.page.zone_num()).rev() {
+            self.mutex[i].unlock_no_int();
+        }
+    }
+
+    /// ### 区域是否存在
+    /// 对外接口先检查区域编号，之后加锁、访问页面数组不会越界
+    fn has_zone(&self, zone : ZoneId)->bool {
+        zone.val() < self.page.zone_num()
+    }
+
+    /// ### 已分配页面所在的区域
+    /// 边界只会在空闲页面上移动，已分配的地址所属区域不会改变
+    fn zone_of_page(&self, addr : *mut u8)->ZoneId {
+        match self.page.zone_of(addr) {
+            Some(zone) => zone,
+            None => panic!("page error addr {:x}", addr as usize),
+        }
+    }
+
+    /// ### 堆内存申请失败时，估计需要的页面数
+    fn page_num_of(&self, size : usize)->usize {
+        self.memory.pool_page_num(size)
+    }
+
+    /// ### 检查配额，需持有区域的锁
+    /// 只限制用户区域，page_num、byte_num 为将要新增的页面数与堆内存字节数
+    fn check_quota(&self, zone : ZoneId, owner : OwnerId, page_num : usize, byte_num : usize)->Result<(), MemoryError> {
+        if zone != ZoneId::USER {
+            return Ok(());
+        }
+        self.quota.check(owner, page_num, byte_num)
+    }
+
+    /// ### 统计 owner 在用户区域的使用量，需持有用户区域的锁
+    /// 只在开始计数或批量释放后调用，平时的使用量在申请、释放时增减
+    fn usage_of(&self, owner : OwnerId)->Usage {
+        if !self.has_zone(ZoneId::USER) {
+            return Usage::ZERO;
+        }
+        Usage {
+            page_num : self.page.owned_page_num(owner, ZoneId::USER),
+            byte_num : self.memory.owned_memory_size(owner, ZoneId::USER),
+        }
+    }
+
+    /// ### 堆内存申请成功后，用户区域记入 owner 的使用量，需持有区域的锁
+    /// free 为申请前区域的空闲页面数，新建内存池占用的页面按前后之差记入
+    fn charge_heap(&mut self, zone : ZoneId, owner : OwnerId, free : usize, byte_num : usize) {
+        if zone == ZoneId::USER {
+            let page_num = free.saturating_sub(self.page.free_page_num(zone));
+            self.quota.charge(owner, page_num, byte_num);
+        }
+    }
+
+    /// ### 释放堆内存，用户区域扣除块所属进程的使用量，需持有区域的锁
+    /// blocks 为要释放的块，free 释放后归还的内存池页面一并扣除
+    fn free_heap(&mut self, zone : ZoneId, blocks : &[*mut u8], free : impl FnOnce(&mut T2)) {
+        if zone != ZoneId::USER {
+            free(&mut self.memory);
+            return;
+        }
+        let mut owner = NO_OWNER;
+        let mut byte_num = 0;
+        for addr in blocks {
+            let (o, size) = self.memory.block_of(*addr, zone);
+            owner = o;
+            byte_num += size;
+        }
+        let free_num = self.page.free_page_num(zone);
+        free(&mut self.memory);
+        let page_num = self.page.free_page_num(zone).saturating_sub(free_num);
+        self.quota.uncharge(owner, page_num, byte_num);
+    }
+
+    /// ### 一段页面释放后的处理，需持有区域的锁
+    /// 扣除所属进程的使用量并移出可移动页面登记表，内核区域补齐保留页面
+    fn page_freed(&mut self, zone : ZoneId, info : PageInfo) {
+        if zone == ZoneId::USER {
+            self.quota.uncharge(info.owner, info.page_num, 0);
         }
+        self.migrate_mutex.lock_no_int();
+        self.migrate.unregister(info.head);
+        self.migrate_mutex.unlock_no_int();
+        if zone.is_kernel() {
+            self.refill_reserve();
+        }
+    }
+
+    /// ### 申请页面，失败时按 retry 的步骤处理后重试
+    /// alloc 在持有区域的锁时调用，之前先检查 owner 的页面配额，req 需与 alloc 的地址限制一致
+    fn alloc_page_with(&mut self, req : PageRequest, zone : ZoneId, owner : OwnerId,
+            alloc : impl Fn(&mut T1)->Option<*mut u8>)->Result<*mut u8, MemoryError> {
+        let num = req.num;
+        if !self.has_zone(zone) {
+            return Err(MemoryError::InvalidZone);
+        }
+        // 参数无效时直接返回，不值得移动边界、迁移或回收
+        if !req.is_valid(self.page.page_size()) {
+            return Err(MemoryError::InvalidRequest);
+        }
+        let mut stage = 0;
+        loop {
+            self.lock(zone);
+            let rt = self.check_quota(zone, owner, num, 0)
+                .and_then(|_| alloc(&mut self.page).ok_or(MemoryError::OutOfMemory));
+            if let (Ok(addr), true) = (rt, owner != NO_OWNER) {
+                self.page.set_owner(addr, owner);
+            }
+            if rt.is_ok() && zone == ZoneId::USER {
+                self.quota.charge(owner, num, 0);
+            }
+            self.unlock(zone);
+            if rt != Err(MemoryError::OutOfMemory) || !self.retry(&mut stage, req, zone) {
+                return rt;
+            }
+        }
+    }
+
+    /// ### 申请失败后的处理，返回是否值得重试
+    /// stage 由调用者保存，初始为 0：依次尝试移动区域边界、迁移页面、请求 shrinker 释放、调用 OOM 处理函数
+    fn retry(&mut self, stage : &mut usize, req : PageRequest, zone : ZoneId)->bool {
+        let page_num = req.num;
+        if *stage == 0 {
+            *stage = 1;
+            // 移动边界会修改相邻的区域，需要持有所有区域的锁
+            self.lock_all();
+            let moved = self.page.expand_zone(zone, page_num);
+            self.unlock_all();
+            if moved {
+                return true;
+            }
+        }
+        if *stage == 1 {
+            *stage = 2;
+            self.lock(zone);
+            // 空闲页面足够但不连续时才值得迁移，有地址限制时最长的空闲段也可能不合要求
+            let fragmented = !req.is_plain() || self.page.largest_free_run(zone) < page_num;
+            let worth = self.page.free_page_num(zone) >= page_num && fragmented;
+            self.unlock(zone);
+            if worth && self.migrate(req, zone) {
+                return true;
+            }
+        }
+        if *stage == 2 {
+            *stage = 3;
+            if self.reclaim.shrink(page_num) > 0 {
+                return true;
+            }
+        }
+        if *stage == 3 {
+            *stage = 4;
+            return self.reclaim.oom(page_num, zone);
+        }
+        false
+    }
+
+    /// ### 搬走窗口内的可移动页面，腾出满足 req 的连续空闲页面
+    /// 持有所有区域的锁复制页面，解锁后调用重定位回调，之后才释放窗口内的旧页面，
+    /// 回调返回之前旧页面不会交给别人
+    fn migrate(&mut self, req : PageRequest, zone : ZoneId)->bool {
+        let page_size = self.page.page_size();
+        let mut moved = [None; MIGRATE_NUM];
+        self.lock_all();
+        self.migrate_mutex.lock_no_int();
+        let window = self.move_window(req, zone, &mut moved);
+        self.migrate_mutex.unlock_no_int();
+        self.unlock_all();
+        let (start, mut rt) = match window {
+            Some(window) => window,
+            None => return false,
+        };
+        for (m, new) in moved.iter().flatten() {
+            (m.relocate)(m.addr, *new, m.page_num);
+        }
+        // 搬走的旧页面与先前占住的页面都已不在登记表中，最后一起释放
+        let end = start + req.num * page_size;
+        self.lock_all();
+        self.migrate_mutex.lock_no_int();
+        let mut addr = start;
+        while addr < end {
+            let info = self.page.page_info(addr as *mut u8).unwrap();
+            if !info.is_free() && !info.has(PageBit::Reserved) && !self.migrate.contains(info.head) {
+                self.page.free_page(info.head);
+            }
+            addr = (addr + page_size).max(info.head as usize + info.page_num * page_size);
+        }
+        // 只有窗口内的页面全部空闲才算成功
+        let page = &self.page;
+        rt = rt && (start..end).step_by(page_size)
+            .all(|addr| page.page_info(addr as *mut u8).unwrap().is_free());
+        self.migrate_mutex.unlock_no_int();
+        self.unlock_all();
+        rt
     }
 
+    /// ### 占住窗口并把其中登记的页面复制到新位置，需持有所有区域与登记表的锁
+    /// 返回窗口起始地址及是否全部搬走，搬走的页面记录在 moved 中，旧页面暂不释放
+    fn move_window(&mut self, req : PageRequest, zone : ZoneId,
+            moved : &mut [Option<(Movable, *mut u8)>])->Option<(usize, bool)> {
+        let page_size = self.page.page_size();
+        // 先去掉失效的登记，如已被其它途径释放、或被重新申请成不同长度的页面
+        let page = &self.page;
+        self.migrate.retain(|m| matches!(page.page_info(m.addr), Some(info)
+            if !info.is_free() && info.head == m.addr && info.page_num == m.page_num &&
+                !info.has(PageBit::Reserved) && !info.has(PageBit::Offline)));
+        let table = &self.migrate;
+        let start = self.page.movable_window(req.num, req.align, req.max_addr, zone,
+            |addr| table.contains(addr))? as usize;
+        let end = start + req.num * page_size;
+        // 先占住窗口内的空闲页面，使新位置不会落在窗口内
+        for addr in (start..end).step_by(page_size) {
+            self.page.claim_pages(addr as *mut u8, 1);
+        }
+        let mut cnt = 0;
+        for m in self.migrate.iter_mut() {
+            let old = m.addr as usize;
+            if old >= end || old + m.page_num * page_size <= start {
+                continue;
+            }
+            let info = self.page.page_info(m.addr).unwrap();
+            if !self.page.can_move(m.addr) {
+                return Some((start, false));
+            }
+            // 大页搬到新位置后仍需自然对齐
+            let new = if info.has(PageBit::Huge) {
+                self.page.alloc_pages_aligned(m.page_num, m.page_num * page_size, zone)
+            }
+            else {
+                self.page.alloc_page(m.page_num, zone)
+            };
+            let new = match new {
+                Some(new) => new,
+                None => return Some((start, false)),
+            };
+            self.page.set_tag(new, info.tag);
+            self.page.set_owner(new, info.owner);
+            for bit in PageBit::USAGE.iter().filter(|bit| info.has(**bit)) {
+                self.page.set_flag(new, *bit);
+            }
+            unsafe {
+                m.addr.copy_to_nonoverlapping(new, m.page_num * page_size);
+            }
+            moved[cnt] = Some((*m, new));
+            cnt += 1;
+            m.addr = new;
+        }
+        Some((start, true))
+    }
+
+    /// ## 泄漏追踪记录
+    /// 遍历期间调用者需保证没有其它核在申请、释放堆内存
+    #[cfg(feature = "leak-tracker")]
+    pub fn tracker(&self)->&LeakTracker {
+        self.memory.tracker()
+    }
 }
 
 impl<T1 : PageOp, T2 : HeapOp<T1>> MemoryOp for MemoryManager<T1, T2> {
     fn kernel_page(&mut self, num : usize)->Option<*mut u8> {
-        self.kernel_mutex.lock_no_int();
-        let rt = self.page.alloc_kernel_page(num).unwrap();
-        self.kernel_mutex.unlock_no_int();
-        Some(rt)
+        self.alloc_page(num, ZoneId::KERNEL)
     }
 
     fn user_page(&mut self, num : usize)->Option<*mut u8> {
-        self.user_mutex.lock_no_int();
-        let rt = self.page.alloc_user_page(num);
-        self.user_mutex.unlock_no_int();
+        self.alloc_page(num, ZoneId::USER)
+    }
+
+    fn alloc_page(&mut self, num : usize, zone : ZoneId)->Option<*mut u8> {
+        self.alloc_page_with(PageRequest::new(num), zone, NO_OWNER, |page| page.alloc_page(num, zone)).ok()
+    }
+
+    fn alloc_pages_aligned(&mut self, num : usize, align : usize, zone : ZoneId)->Option<*mut u8> {
+        self.alloc_page_with(PageRequest::aligned(num, align, usize::MAX), zone, NO_OWNER,
+            |page| page.alloc_pages_aligned(num, align, zone)).ok()
+    }
+
+    fn claim_pages(&mut self, addr : *mut u8, num : usize)->bool {
+        // 空闲页面所属的区域可能随边界移动，需要持有所有区域的锁
+        self.lock_all();
+        let zone = self.page.zone_of(addr);
+        let rt = match zone {
+            Some(zone) if self.check_quota(zone, NO_OWNER, num, 0).is_err() => false,
+            _ => self.page.claim_pages(addr, num),
+        };
+        if rt && zone == Some(ZoneId::USER) {
+            self.quota.charge(NO_OWNER, num, 0);
+        }
+        self.unlock_all();
+        rt
+    }
+
+    fn alloc_huge_page(&mut self, order : usize, zone : ZoneId)->Option<*mut u8> {
+        let num = huge_page_num(order, self.page.page_size())?;
+        let req = PageRequest::aligned(num, num * self.page.page_size(), usize::MAX);
+        self.alloc_page_with(req, zone, NO_OWNER, |page| page.alloc_huge_page(order, zone)).ok()
+    }
+
+    fn kernel_page_critical(&mut self, num : usize)->Option<*mut u8> {
+        self.lock(ZoneId::KERNEL);
+        let rt = self.page.alloc_kernel_page_critical(num);
+        self.unlock(ZoneId::KERNEL);
         rt
     }
 
     fn free_page(&mut self, addr : *mut u8) {
-        if addr as usize >= self.user_start as usize {
-            self.user_mutex.lock_no_int();
-            self.page.free_page(addr);
-            self.user_mutex.unlock_no_int();
+        self.put_page(addr);
+    }
+
+    fn get_page(&mut self, addr : *mut u8) {
+        let zone = self.zone_of_page(addr);
+        self.lock(zone);
+        self.page.get_page(addr);
+        self.unlock(zone);
+    }
+
+    fn put_page(&mut self, addr : *mut u8)->bool {
+        let zone = self.zone_of_page(addr);
+        self.lock(zone);
+        // 释放后页面信息不再可查，先记下所有者
+        let info = self.page.page_info(addr);
+        let rt = self.page.put_page(addr);
+        if let (true, Some(info)) = (rt, info) {
+            self.page_freed(zone, info);
         }
-        else {
-            self.kernel_mutex.lock_no_int();
-            self.page.free_page(addr);
-            self.kernel_mutex.unlock_no_int();
+        self.unlock(zone);
+        rt
+    }
+
+    fn page_count(&mut self, addr : *mut u8)->usize {
+        let zone = self.zone_of_page(addr);
+        self.lock(zone);
+        let rt = self.page.page_count(addr);
+        self.unlock(zone);
+        rt
+    }
+
+    fn alloc_dma(&mut self, num : usize, max_addr : usize, align : usize)->Option<*mut u8> {
+        self.alloc_page_with(PageRequest::aligned(num, align, max_addr), ZoneId::DMA, NO_OWNER,
+            |page| page.alloc_dma_page(num, max_addr, align)).ok()
+    }
+
+    #[cfg_attr(feature = "leak-tracker", track_caller)]
+    fn alloc_memory_atomic(&mut self, size : usize)->Option<*mut u8> {
+        self.lock(ZoneId::KERNEL);
+        let mut rt = self.memory.alloc_memory(size, ZoneId::KERNEL);
+        if rt.is_none() && size <= self.page.page_size() {
+            rt = self.reserve.take(self.page.page_size());
         }
+        self.unlock(ZoneId::KERNEL);
+        rt
+    }
+
+    fn reserve_page_num(&mut self)->usize {
+        self.lock(ZoneId::KERNEL);
+        let rt = self.reserve.available();
+        self.unlock(ZoneId::KERNEL);
+        rt
     }
 
-    fn alloc_memory(&mut self, size : usize, is_kernel : bool)->Option<*mut u8> {
-        let rt;
-        if is_kernel {
-            self.kernel_mutex.lock_no_int();
-            rt = self.memory.alloc_kernel_memory(size);
-            self.kernel_mutex.unlock_no_int();
+    #[cfg_attr(feature = "leak-tracker", track_caller)]
+    fn alloc_memory(&mut self, size : usize, zone : ZoneId)->Option<*mut u8> {
+        if !self.has_zone(zone) {
+            return None;
         }
-        else {
-            self.user_mutex.lock_no_int();
-            rt = self.memory.alloc_user_memory(size);
-            self.user_mutex.unlock_no_int();
+        let mut stage = 0;
+        loop {
+            self.lock_heap(zone);
+            let (page_num, byte_num) = (self.memory.pool_page_need(size, zone, NO_OWNER),
+                self.memory.block_size(size, zone, NO_OWNER));
+            let free = self.page.free_page_num(zone);
+            let rt = match self.check_quota(zone, NO_OWNER, page_num, byte_num) {
+                Ok(()) => self.memory.alloc_memory(size, zone).ok_or(MemoryError::OutOfMemory),
+                Err(err) => Err(err),
+            };
+            if rt.is_ok() {
+                self.charge_heap(zone, NO_OWNER, free, byte_num);
+            }
+            self.unlock_heap(zone);
+            if rt != Err(MemoryError::OutOfMemory) ||
+                    !self.retry(&mut stage, PageRequest::new(self.page_num_of(size)), zone) {
+                return rt.ok();
+            }
+        }
+    }
+
+    fn alloc_movable(&mut self, size : usize, zone : ZoneId)->Option<MovableHandle> {
+        if !self.has_zone(zone) {
+            return None;
+        }
+        let mut stage = 0;
+        loop {
+            self.lock(zone);
+            let (page_num, byte_num) = (self.memory.movable_page_need(size, zone),
+                self.memory.movable_size(size, zone));
+            let free = self.page.free_page_num(zone);
+            let rt = match self.check_quota(zone, NO_OWNER, page_num, byte_num) {
+                Ok(()) => self.memory.alloc_movable(size, zone).ok_or(MemoryError::OutOfMemory),
+                Err(err) => Err(err),
+            };
+            if rt.is_ok() {
+                self.charge_heap(zone, NO_OWNER, free, byte_num);
+            }
+            self.unlock(zone);
+            if rt != Err(MemoryError::OutOfMemory) ||
+                    !self.retry(&mut stage, PageRequest::new(self.page_num_of(size)), zone) {
+                return rt.ok();
+            }
+        }
+    }
+
+    fn free_movable(&mut self, handle : MovableHandle) {
+        let zone = self.zone_of_page(handle.slot() as *mut u8);
+        self.lock(zone);
+        let blocks = [handle.addr(), handle.slot() as *mut u8];
+        self.free_heap(zone, &blocks, |heap| heap.free_movable(handle, zone));
+        self.unlock(zone);
+    }
+
+    fn compact(&mut self)->usize {
+        let mut cnt = 0;
+        for zone in (0..self.page.zone_num()).map(ZoneId) {
+            self.lock(zone);
+            let num = self.memory.compact(zone);
+            // 可移动内存池都不属于任何进程
+            if zone == ZoneId::USER {
+                self.quota.uncharge(NO_OWNER, num, 0);
+            }
+            cnt += num;
+            if zone.is_kernel() {
+                self.refill_reserve();
+            }
+            self.unlock(zone);
+        }
+        cnt
+    }
+
+    #[cfg_attr(feature = "leak-tracker", track_caller)]
+    fn alloc_tagged(&mut self, size : usize, tag : Tag)->Option<*mut u8> {
+        let mut stage = 0;
+        loop {
+            self.lock(ZoneId::KERNEL);
+            let rt = self.memory.alloc_tagged_memory(size, tag);
+            self.unlock(ZoneId::KERNEL);
+            let req = PageRequest::new(self.page_num_of(size));
+            if rt.is_some() || !self.retry(&mut stage, req, ZoneId::KERNEL) {
+                return rt;
+            }
         }
+    }
+
+    fn kernel_page_tagged(&mut self, num : usize, tag : Tag)->Option<*mut u8> {
+        self.alloc_page_with(PageRequest::new(num), ZoneId::KERNEL, NO_OWNER, |page| {
+            let rt = page.alloc_page(num, ZoneId::KERNEL)?;
+            page.set_tag(rt, tag);
+            Some(rt)
+        }).ok()
+    }
+
+    fn tag_usage(&mut self, tag : Tag)->TagUsage {
+        self.lock_all();
+        let rt = TagUsage {
+            page_num : self.page.tag_page_num(tag),
+            heap_size : self.memory.tag_memory_size(tag),
+        };
+        self.unlock_all();
         rt
     }
 
-    fn free_memory(&mut self, addr : *mut u8) {
-        if addr >= self.kernel_start && addr < self.user_start {
-            self.kernel_mutex.lock_no_int();
-            self.memory.free_kernel_memory(addr);
-            self.kernel_mutex.unlock_no_int();
+    fn user_page_owned(&mut self, num : usize, owner : OwnerId)->Result<*mut u8, MemoryError> {
+        self.alloc_page_with(PageRequest::new(num), ZoneId::USER, owner,
+            |page| page.alloc_page(num, ZoneId::USER))
+    }
+
+    #[cfg_attr(feature = "leak-tracker", track_caller)]
+    fn alloc_owned(&mut self, size : usize, owner : OwnerId)->Result<*mut u8, MemoryError> {
+        if !self.has_zone(ZoneId::USER) {
+            return Err(MemoryError::InvalidZone);
+        }
+        let mut stage = 0;
+        loop {
+            self.lock_heap(ZoneId::USER);
+            let (page_num, byte_num) = (self.memory.pool_page_need(size, ZoneId::USER, owner),
+                self.memory.block_size(size, ZoneId::USER, owner));
+            let free = self.page.free_page_num(ZoneId::USER);
+            let rt = match self.check_quota(ZoneId::USER, owner, page_num, byte_num) {
+                Ok(()) => self.memory.alloc_owned_memory(size, owner).ok_or(MemoryError::OutOfMemory),
+                Err(err) => Err(err),
+            };
+            if rt.is_ok() {
+                self.charge_heap(ZoneId::USER, owner, free, byte_num);
+            }
+            self.unlock_heap(ZoneId::USER);
+            if rt != Err(MemoryError::OutOfMemory) ||
+                    !self.retry(&mut stage, PageRequest::new(self.page_num_of(size)), ZoneId::USER) {
+                return rt;
+            }
+        }
+    }
+
+    fn free_all_owned_by(&mut self, owner : OwnerId)->usize {
+        self.lock_all();
+        self.memory.remove_owned_pools(owner);
+        let rt = self.page.free_all_owned_by(owner);
+        let page = &self.page;
+        self.migrate_mutex.lock_no_int();
+        self.migrate.retain(|m| matches!(page.page_info(m.addr), Some(info) if !info.is_free()));
+        self.migrate_mutex.unlock_no_int();
+        // 固定的页面仍归此进程，共享的页面转给了 NO_OWNER，两者都重新统计
+        for owner in [owner, NO_OWNER].iter().copied() {
+            let usage = self.usage_of(owner);
+            self.quota.recount(owner, usage);
+        }
+        self.unlock_all();
+        rt
+    }
+
+    fn set_quota(&mut self, owner : OwnerId, quota : Quota)->Result<(), MemoryError> {
+        self.lock(ZoneId::USER);
+        // 新加入表中的所有者从现有的使用量开始计数
+        let fresh = !self.quota.is_tracked(owner);
+        let rt = self.quota.set(owner, quota);
+        if rt.is_ok() && fresh {
+            let usage = self.usage_of(owner);
+            self.quota.recount(owner, usage);
+        }
+        self.unlock(ZoneId::USER);
+        rt
+    }
+
+    fn clear_quota(&mut self, owner : OwnerId) {
+        self.lock(ZoneId::USER);
+        self.quota.clear(owner);
+        self.unlock(ZoneId::USER);
+    }
+
+    fn register_shrinker(&mut self, shrinker : Shrinker)->Result<usize, MemoryError> {
+        self.lock(ZoneId::KERNEL);
+        let rt = self.reclaim.register(shrinker);
+        self.unlock(ZoneId::KERNEL);
+        rt
+    }
+
+    fn unregister_shrinker(&mut self, idx : usize) {
+        self.lock(ZoneId::KERNEL);
+        self.reclaim.unregister(idx);
+        self.unlock(ZoneId::KERNEL);
+    }
+
+    fn set_oom_handler(&mut self, handler : Option<OomHandler>) {
+        self.lock(ZoneId::KERNEL);
+        self.reclaim.set_oom_handler(handler);
+        self.unlock(ZoneId::KERNEL);
+    }
+
+    fn free_page_num(&mut self, zone : ZoneId)->usize {
+        if !self.has_zone(zone) {
+            return 0;
         }
-        else if addr >= self.user_start {
-            self.user_mutex.lock_no_int();
-            self.memory.free_user_memory(addr);
-            self.user_mutex.unlock_no_int();
+        self.lock(zone);
+        let rt = self.page.free_page_num(zone);
+        self.unlock(zone);
+        rt
+    }
+
+    fn set_watermark(&mut self, zone : ZoneId, mark : Watermark)->Result<(), MemoryError> {
+        if !self.has_zone(zone) {
+            return Err(MemoryError::InvalidZone);
+        }
+        self.lock(zone);
+        let rt = self.page.set_watermark(zone, mark);
+        self.unlock(zone);
+        rt
+    }
+
+    fn is_low_memory(&mut self, zone : ZoneId)->bool {
+        if !self.has_zone(zone) {
+            return false;
+        }
+        self.lock(zone);
+        let rt = self.page.is_low_memory(zone);
+        self.unlock(zone);
+        rt
+    }
+
+    fn set_low_memory_handler(&mut self, handler : Option<LowMemoryHandler>) {
+        self.lock_all();
+        self.page.set_low_memory_handler(handler);
+        self.unlock_all();
+    }
+
+    fn reserve_range(&mut self, range : Range<usize>)->bool {
+        self.lock_all();
+        let rt = self.page.reserve_range(range);
+        self.unlock_all();
+        rt
+    }
+
+    fn pin_pages(&mut self, addr : *mut u8, num : usize)->bool {
+        let zone = self.zone_of_page(addr);
+        self.lock(zone);
+        let rt = self.page.pin_pages(addr, num);
+        self.unlock(zone);
+        rt
+    }
+
+    fn unpin_pages(&mut self, addr : *mut u8, num : usize) {
+        let zone = self.zone_of_page(addr);
+        self.lock(zone);
+        let info = self.page.page_info(addr);
+        if let (true, Some(info)) = (self.page.unpin_pages(addr, num), info) {
+            self.page_freed(zone, info);
+        }
+        self.unlock(zone);
+    }
+
+    fn pinned_page_num(&mut self, zone : ZoneId)->usize {
+        if !self.has_zone(zone) {
+            return 0;
+        }
+        self.lock(zone);
+        let rt = self.page.pinned_page_num(zone);
+        self.unlock(zone);
+        rt
+    }
+
+    fn largest_free_run(&mut self, zone : ZoneId)->usize {
+        if !self.has_zone(zone) {
+            return 0;
+        }
+        self.lock(zone);
+        let rt = self.page.largest_free_run(zone);
+        self.unlock(zone);
+        rt
+    }
+
+    fn free_run_histogram(&mut self, zone : ZoneId)->[usize; FREE_RUN_ORDER] {
+        if !self.has_zone(zone) {
+            return [0; FREE_RUN_ORDER];
+        }
+        self.lock(zone);
+        let rt = self.page.free_run_histogram(zone);
+        self.unlock(zone);
+        rt
+    }
+
+    fn fragmentation_index(&mut self, zone : ZoneId)->usize {
+        if !self.has_zone(zone) {
+            return 0;
+        }
+        self.lock(zone);
+        let rt = self.page.fragmentation_index(zone);
+        self.unlock(zone);
+        rt
+    }
+
+    fn register_movable(&mut self, addr : *mut u8, relocate : Relocate)->Result<(), MemoryError> {
+        self.lock_all();
+        let info = self.page.page_info(addr);
+        let rt = match info {
+            Some(info) if !info.is_free() && info.head == addr &&
+                    !info.has(PageBit::Reserved) && !info.has(PageBit::Offline) => {
+                self.migrate_mutex.lock_no_int();
+                let rt = self.migrate.register(Movable { addr, page_num : info.page_num, relocate });
+                self.migrate_mutex.unlock_no_int();
+                rt
+            }
+            _ => Err(MemoryError::InvalidPage),
+        };
+        self.unlock_all();
+        rt
+    }
+
+    fn unregister_movable(&mut self, addr : *mut u8) {
+        self.migrate_mutex.lock_no_int();
+        self.migrate.unregister(addr);
+        self.migrate_mutex.unlock_no_int();
+    }
+
+    fn migrate_pages(&mut self, num : usize, zone : ZoneId)->bool {
+        if !self.has_zone(zone) || num == 0 {
+            return false;
+        }
+        self.migrate(PageRequest::new(num), zone)
+    }
+
+    fn set_page_flag(&mut self, addr : *mut u8, bit : PageBit) {
+        let zone = self.zone_of_page(addr);
+        self.lock(zone);
+        self.page.set_flag(addr, bit);
+        self.unlock(zone);
+    }
+
+    fn clear_page_flag(&mut self, addr : *mut u8, bit : PageBit) {
+        let zone = self.zone_of_page(addr);
+        self.lock(zone);
+        self.page.clear_flag(addr, bit);
+        self.unlock(zone);
+    }
+
+    fn page_info(&mut self, addr : *mut u8)->Option<PageInfo> {
+        self.lock_all();
+        let rt = self.page.page_info(addr);
+        self.unlock_all();
+        rt
+    }
+
+    fn offline_range(&mut self, start : usize, len : usize)->Result<usize, *mut u8> {
+        self.lock_all();
+        let rt = self.page.offline_range(start, len);
+        self.unlock_all();
+        rt
+    }
+
+    fn online_range(&mut self, start : usize, len : usize)->usize {
+        self.lock_all();
+        let rt = self.page.online_range(start, len);
+        self.unlock_all();
+        rt
+    }
+
+    fn add_region(&mut self, start : usize, len : usize, zone : ZoneId)->Result<(), MemoryError> {
+        self.lock_all();
+        let rt = self.page.add_region(start, len, zone);
+        self.unlock_all();
+        rt
+    }
+
+    fn free_memory(&mut self, addr : *mut u8) {
+        // 边界只会在空闲页面上移动，已分配的地址所属区域不会改变
+        let zone = match self.page.zone_of(addr) {
+            Some(zone) => zone,
+            None => panic!("free memory error addr {:x}", addr as usize),
+        };
+        self.lock_heap(zone);
+        if !zone.is_kernel() {
+            self.free_heap(zone, &[addr], |heap| heap.free_memory(addr, zone));
         }
         else {
-            panic!("free memory error addr {:x}", addr as usize);
+            if !self.reserve.give_back(addr, &mut self.page) {
+                self.memory.free_memory(addr, zone);
+            }
+            self.refill_reserve();
         }
+        self.unlock_heap(zone);
     }
 
     fn print(&mut self) {
-        self.kernel_mutex.lock_no_int();
+        self.lock(ZoneId::KERNEL);
         self.page.print();
         self.memory.print();
-        self.kernel_mutex.unlock_no_int();
+        self.unlock(ZoneId::KERNEL);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::{ptr::null_mut, sync::atomic::{AtomicPtr, AtomicUsize, Ordering}};
+    use std::{boxed::Box, sync::Mutex, vec::Vec};
+
+    use crate::{MemoryOp, NO_OWNER, PageOp, Quota, Watermark, config::{PAGE_SIZE, ZONE_NUM}, error::MemoryError,
+        page::PageBit, testing::{Arena, Manager}, zone::ZoneId};
+
+    #[test]
+    fn free_all_owned_by() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        let free = mgr.free_page_num(ZoneId::USER);
+        let other = mgr.alloc_owned(40, 7).unwrap();
+        let before = mgr.free_page_num(ZoneId::USER);
+        for i in 0..100 {
+            mgr.alloc_owned(8 + i * 37 % 3000, 3).unwrap();
+        }
+        mgr.user_page_owned(2, 3).unwrap();
+        let unowned = mgr.user_page(1).unwrap();
+        let used = before - mgr.free_page_num(ZoneId::USER);
+        // 页面与内存池所在的页面一次释放
+        assert_eq!(mgr.free_all_owned_by(3), used - 1);
+        assert_eq!(mgr.free_all_owned_by(3), 0);
+        assert_eq!(mgr.free_page_num(ZoneId::USER), before - 1);
+        // 释放后的内存可以再次使用，其它所有者的内存不受影响
+        for i in 0..100 {
+            mgr.alloc_owned(8 + i * 37 % 3000, 4).unwrap();
+        }
+        assert_eq!(mgr.free_all_owned_by(4), used - 3);
+        unsafe {
+            *other = 5;
+        }
+        mgr.free_page(unowned);
+        mgr.free_all_owned_by(7);
+        assert_eq!(mgr.free_page_num(ZoneId::USER), free);
+    }
+
+    #[test]
+    fn free_shared_owned() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        let free = mgr.free_page_num(ZoneId::USER);
+        let shared = mgr.user_page_owned(2, 3).unwrap();
+        mgr.user_page_owned(1, 3).unwrap();
+        mgr.get_page(shared);
+        // 仍被共享的页面只放弃一次引用，之后不再属于该进程
+        assert_eq!(mgr.free_all_owned_by(3), 1);
+        assert_eq!(mgr.page_count(shared), 1);
+        assert_eq!(mgr.page_info(shared).unwrap().owner, NO_OWNER);
+        assert_eq!(mgr.free_all_owned_by(3), 0);
+        assert_eq!(mgr.page_count(shared), 1);
+        assert!(mgr.put_page(shared));
+        assert_eq!(mgr.free_page_num(ZoneId::USER), free);
+    }
+
+    #[test]
+    fn move_boundary() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        let kernel = mgr.free_page_num(ZoneId::KERNEL);
+        let user = mgr.free_page_num(ZoneId::USER);
+        // 内核区域不够时从用户区域底部取得页面
+        let addr = mgr.kernel_page(kernel + 10).unwrap();
+        assert_eq!(mgr.free_page_num(ZoneId::KERNEL), 0);
+        assert_eq!(mgr.free_page_num(ZoneId::USER), user - 10);
+        let last = unsafe {addr.add((kernel + 9) * PAGE_SIZE)};
+        assert_eq!(mgr.page_info(last).unwrap().head, addr);
+        // 按地址释放到新的区域
+        mgr.free_page(addr);
+        assert_eq!(mgr.free_page_num(ZoneId::KERNEL), kernel + 10);
+        // 用户区域同样可以从内核区域顶部取回页面
+        let addr = mgr.user_page(user - 5).unwrap();
+        assert_eq!(mgr.free_page_num(ZoneId::KERNEL), kernel + 5);
+        assert_eq!(mgr.free_page_num(ZoneId::USER), 0);
+        let ptr = mgr.alloc_memory(64, ZoneId::USER).unwrap();
+        assert!(ptr < addr);
+        let held = kernel + user - (user - 5) -
+            mgr.free_page_num(ZoneId::KERNEL) - mgr.free_page_num(ZoneId::USER);
+        mgr.free_memory(ptr);
+        mgr.free_page(addr);
+        // 堆内存保留最后一个空的内存池
+        assert_eq!(mgr.free_page_num(ZoneId::KERNEL) + mgr.free_page_num(ZoneId::USER), kernel + user - held);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn many_zones() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = Manager::with_zones(arena.start(), &[64, 32, 32], &[], PAGE_SIZE, arena.end());
+        // 剩余内存全部归最后一个区域
+        assert_eq!(mgr.free_page_num(ZoneId(3)), (1 << 20) / PAGE_SIZE - 128);
+        let mut last = 0;
+        for i in 0..4 {
+            let zone = ZoneId(i);
+            let free = mgr.free_page_num(zone);
+            let page = mgr.alloc_page(1, zone).unwrap();
+            let ptr = mgr.alloc_memory(100, zone).unwrap();
+            // 区域按地址从低到高排列，各自有独立的内存池
+            assert!(page as usize > last && ptr as usize > last);
+            last = page.max(ptr) as usize;
+            assert!(mgr.free_page_num(zone) < free);
+            mgr.free_memory(ptr);
+            mgr.free_page(page);
+        }
+    }
+
+    #[test]
+    fn no_such_zone() {
+        let arena = Arena::new(1 << 20);
+        // 只有 DMA 与内核两个区域
+        let mut mgr = Manager::with_zones(arena.start(), &[32], &[], PAGE_SIZE, arena.end());
+        for zone in [ZoneId::USER, ZoneId(ZONE_NUM), ZoneId(usize::MAX)] {
+            assert!(mgr.alloc_page(1, zone).is_none());
+            assert!(mgr.alloc_pages_aligned(1, PAGE_SIZE, zone).is_none());
+            assert!(mgr.alloc_huge_page(1, zone).is_none());
+            assert!(mgr.alloc_memory(8, zone).is_none());
+            assert!(mgr.alloc_movable(8, zone).is_none());
+            assert_eq!(mgr.free_page_num(zone), 0);
+            assert!(!mgr.is_low_memory(zone));
+            assert_eq!(mgr.largest_free_run(zone), 0);
+            assert!(!mgr.migrate_pages(1, zone));
+            assert_eq!(mgr.set_watermark(zone, Watermark::new(0, 0, 0)), Err(MemoryError::InvalidZone));
+        }
+        assert!(mgr.user_page(1).is_none());
+        assert_eq!(mgr.user_page_owned(1, 3), Err(MemoryError::InvalidZone));
+        assert_eq!(mgr.alloc_owned(8, 3), Err(MemoryError::InvalidZone));
+        mgr.set_quota(3, Quota::UNLIMITED).unwrap();
+        assert_eq!(mgr.free_all_owned_by(3), 0);
+        mgr.kernel_page(1).unwrap();
+    }
+
+    static MOVED : Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+    fn relocate(old : *mut u8, new : *mut u8, page_num : usize) {
+        assert_eq!(page_num, 1);
+        MOVED.lock().unwrap().push((old as usize, new as usize));
+    }
+
+    #[test]
+    fn migrate() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        while mgr.free_page_num(ZoneId::KERNEL) > 0 {
+            mgr.kernel_page(1).unwrap();
+        }
+        let mut user = Vec::new();
+        while let Ok(addr) = mgr.user_page_owned(1, 3) {
+            user.push(addr);
+        }
+        user.sort();
+        // 隔一页释放一页，其余登记为可移动
+        for (i, addr) in user.iter().enumerate() {
+            if i % 2 == 0 {
+                mgr.free_page(*addr);
+            }
+            else {
+                unsafe {
+                    *(*addr as *mut usize) = *addr as usize;
+                }
+                mgr.set_page_flag(*addr, PageBit::Dirty);
+                mgr.register_movable(*addr, relocate).unwrap();
+            }
+        }
+        // 被共享、固定的页面不移动
+        mgr.get_page(user[5]);
+        assert!(mgr.pin_pages(user[3], 1));
+        assert!(mgr.migrate_pages(4, ZoneId::USER));
+        let moved = MOVED.lock().unwrap().clone();
+        assert_eq!(moved.iter().map(|m| m.0).collect::<Vec<_>>(), [user[7] as usize, user[9] as usize]);
+        for (old, new) in moved {
+            let info = mgr.page_info(new as *mut u8).unwrap();
+            assert!(info.has(PageBit::Dirty));
+            assert_eq!(info.owner, 3);
+            assert_eq!(info.count, 1);
+            assert_eq!(unsafe {*(new as *const usize)}, old);
+        }
+        assert_eq!(mgr.user_page(4), Some(user[6]));
+        // 没有地方可搬时窗口没有腾空，返回 false
+        while mgr.user_page(1).is_some() {}
+        assert!(!mgr.migrate_pages(2, ZoneId::USER));
+        assert_eq!(mgr.free_page_num(ZoneId::USER), 0);
+        assert_eq!(mgr.page_count(user[5]), 2);
+        assert_eq!(unsafe {*(user[11] as *const usize)}, user[11] as usize);
+    }
+
+    #[test]
+    fn migrate_aligned() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        while mgr.free_page_num(ZoneId::KERNEL) > 0 {
+            mgr.kernel_page(1).unwrap();
+        }
+        let mut user = Vec::new();
+        while let Some(addr) = mgr.user_page(1) {
+            user.push(addr);
+        }
+        user.sort();
+        for (i, addr) in user.iter().enumerate() {
+            if i % 2 == 0 {
+                mgr.free_page(*addr);
+            }
+            else {
+                mgr.register_movable(*addr, |_, _, _| {}).unwrap();
+            }
+        }
+        // 不考虑对齐时找到的第一个窗口不对齐
+        let align = 4 * PAGE_SIZE;
+        if (user[0] as usize).is_multiple_of(align) {
+            assert!(mgr.pin_pages(user[1], 1));
+        }
+        let addr = mgr.alloc_pages_aligned(4, align, ZoneId::USER).unwrap();
+        assert_eq!(addr as usize % align, 0);
+    }
+
+    #[test]
+    fn register_movable() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        let addr = mgr.user_page(2).unwrap();
+        let free = mgr.user_page(1).unwrap();
+        mgr.free_page(free);
+        let offline = mgr.user_page(1).unwrap();
+        mgr.free_page(offline);
+        assert_eq!(mgr.offline_range(offline as usize, PAGE_SIZE), Ok(1));
+        let reserved = mgr.user_page(1).unwrap();
+        mgr.free_page(reserved);
+        assert!(mgr.reserve_range(reserved as usize..reserved as usize + PAGE_SIZE));
+        assert!(mgr.page_info(reserved).unwrap().has(PageBit::Reserved));
+        // 不是一段已分配页面的起始地址
+        for bad in [free, offline, reserved, unsafe {addr.add(PAGE_SIZE)}, arena.end() as *mut u8] {
+            assert_eq!(mgr.register_movable(bad, |_, _, _| {}), Err(MemoryError::InvalidPage));
+        }
+        assert_eq!(mgr.register_movable(addr, |_, _, _| {}), Ok(()));
+    }
+
+    static MANAGER : AtomicPtr<Manager> = AtomicPtr::new(null_mut());
+    static RELOCATED : AtomicUsize = AtomicUsize::new(0);
+
+    fn relocate_unlocked(_old : *mut u8, new : *mut u8, _page_num : usize) {
+        // 回调时不持有锁，可以调用管理器的接口
+        let mgr = unsafe {&mut *MANAGER.load(Ordering::Relaxed)};
+        assert_eq!(mgr.page_count(new), 1);
+        RELOCATED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn migrate_stale() {
+        let arena = Arena::new(1 << 20);
+        let mgr = Box::leak(Box::new(arena.manager(128)));
+        MANAGER.store(mgr, Ordering::Relaxed);
+        while mgr.free_page_num(ZoneId::KERNEL) > 0 {
+            mgr.kernel_page(1).unwrap();
+        }
+        let mut user = Vec::new();
+        while let Some(addr) = mgr.user_page(1) {
+            user.push(addr);
+        }
+        user.sort();
+        for (i, addr) in user.iter().enumerate() {
+            if i % 2 == 0 {
+                mgr.free_page(*addr);
+            }
+            else {
+                mgr.register_movable(*addr, relocate_unlocked).unwrap();
+            }
+        }
+        // 绕过 free_page 释放后重新申请成更长的一段，登记已失效
+        mgr.page.free_page(user[1]);
+        assert!(mgr.claim_pages(user[1], 2));
+        assert!(mgr.migrate_pages(4, ZoneId::USER));
+        assert_eq!(RELOCATED.load(Ordering::Relaxed), 2);
+        let info = mgr.page_info(user[1]).unwrap();
+        assert_eq!((info.head, info.page_num), (user[1], 2));
+        assert_eq!(mgr.user_page(4), Some(user[3]));
+    }
+}
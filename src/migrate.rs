@@ -0,0 +1,68 @@
+//! # 页面迁移
+//! 登记可以移动的页面及其重定位回调，连续页面申请因碎片失败时，
+//! 把一段窗口内的可移动页面搬到别处，腾出连续的空闲页面
+//!
+//! 2021年5月7日 zg
+
+use crate::{config::MIGRATE_NUM, error::MemoryError};
+
+/// ## 重定位回调
+/// 页面内容已复制到新位置后调用，参数为旧地址、新地址及页面数，由使用者更新页表等引用
+/// 调用时不持有锁，旧页面在回调返回后才释放，期间使用者不能释放这段页面
+pub type Relocate = fn(old : *mut u8, new : *mut u8, page_num : usize);
+
+/// ## 可移动的一段页面
+#[derive(Copy, Clone)]
+pub struct Movable {
+    pub addr : *mut u8,
+    pub page_num : usize,
+    pub relocate : Relocate,
+}
+
+pub struct MigrateTable {
+    entry : [Option<Movable>; MIGRATE_NUM],
+}
+
+impl MigrateTable {
+    pub const fn new()->Self {
+        Self {
+            entry : [None; MIGRATE_NUM],
+        }
+    }
+
+    /// ### 登记一段页面，重复登记时替换回调
+    pub fn register(&mut self, movable : Movable)->Result<(), MemoryError> {
+        let idx = match self.entry.iter().position(|e| matches!(e, Some(e) if e.addr == movable.addr)) {
+            Some(idx) => idx,
+            None => self.entry.iter().position(|e| e.is_none())
+                .ok_or(MemoryError::MigrateTableFull)?,
+        };
+        self.entry[idx] = Some(movable);
+        Ok(())
+    }
+
+    pub fn unregister(&mut self, addr : *mut u8) {
+        for e in self.entry.iter_mut() {
+            if matches!(e, Some(m) if m.addr == addr) {
+                *e = None;
+            }
+        }
+    }
+
+    pub fn contains(&self, addr : *mut u8)->bool {
+        self.entry.iter().flatten().any(|e| e.addr == addr)
+    }
+
+    /// ### 去掉不满足条件的登记，如已被释放的页面
+    pub fn retain(&mut self, f : impl Fn(&Movable)->bool) {
+        for e in self.entry.iter_mut() {
+            if matches!(e, Some(m) if !f(m)) {
+                *e = None;
+            }
+        }
+    }
+
+    pub fn iter_mut(&mut self)->impl Iterator<Item = &mut Movable> {
+        self.entry.iter_mut().flatten()
+    }
+}
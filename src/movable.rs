@@ -0,0 +1,31 @@
+//! # 可移动内存
+//! 通过句柄间接访问的堆内存，整理堆内存时可以被搬到其它内存池
+//!
+//! 2021年5月6日 zg
+
+/// 可移动块开头存放句柄槽位的地址，数据紧随其后
+pub(crate) const MOVABLE_HEADER : usize = core::mem::size_of::<usize>();
+
+/// ## 可移动内存句柄
+/// 句柄本身不会移动，指向一个记录数据当前地址的槽位
+/// 通过 MemoryOp::free_movable 释放
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct MovableHandle {
+    slot : *mut *mut u8,
+}
+
+impl MovableHandle {
+    pub(crate) fn new(slot : *mut *mut u8)->Self {
+        Self { slot }
+    }
+
+    pub(crate) fn slot(&self)->*mut *mut u8 {
+        self.slot
+    }
+
+    /// ### 数据的当前地址
+    /// 整理堆内存后会改变，不能在 compact 前后持续使用
+    pub fn addr(&self)->*mut u8 {
+        unsafe {*self.slot}
+    }
+}
@@ -1,177 +1,646 @@
 //! # 内存页管理
 //! 将内存分页进行管理，方便后期页表映射
+//! 内存按地址划分为若干区域，区域信息放在页面数组之前，所有副本共享
 //! 
 //! 2021年1月25日 zg
 
-use core::ptr::slice_from_raw_parts;
-
-use crate::require::PageOp;
+use core::{marker::PhantomData, mem::size_of, ops::Range, ptr::{addr_of_mut, null_mut},
+	slice::{from_raw_parts, from_raw_parts_mut}};
 
+use crate::{config::{FREE_RUN_ORDER, HUGE_PAGE_SHIFT, ZONE_NUM}, error::MemoryError, require::{NO_OWNER, OwnerId, PageOp}, tag::Tag,
+	watermark::{LowMemoryHandler, Watermark}, zone::ZoneId};
 
 pub struct PageManager {
-	kernel_page : &'static mut [Page],
-	kernel_page_num : usize,
-	kernel_start : usize,
-	user_page : &'static mut [Page],
-	user_page_num : usize,
-	user_start : usize,
-	total_num : usize,
+	zone : *mut Zone,
+	zone_num : usize,
 	memory_end : usize,
 	page_size : usize,
 }
 
 
+/// ## order 级大页包含的页面数
+/// order 为 0，或大页超出地址空间时返回 None
+pub(crate) fn huge_page_num(order : usize, page_size : usize)->Option<usize> {
+	let shift = HUGE_PAGE_SHIFT.checked_mul(order)?;
+	if order == 0 || shift >= usize::BITS as usize {
+		return None;
+	}
+	let num = 1usize << shift;
+	num.checked_mul(page_size)?;
+	Some(num)
+}
+
+/// ### 对齐要求是否有效
+/// 2 的幂或页面大小的整数倍，0 无效
+pub(crate) fn is_valid_align(align : usize, page_size : usize)->bool {
+	align.is_power_of_two() || align > 0 && align.is_multiple_of(page_size)
+}
+
 impl PageManager {
-	fn clear(&self, addr : *mut u8, num : usize) {
-		unsafe {
-			let len = num * self.page_size;
-			addr.write_bytes(0, len);
+	fn zone(&self, idx : usize)->&Zone {
+		assert!(idx < self.zone_num, "no such zone: {}", idx);
+		unsafe {&*self.zone.add(idx)}
+	}
+
+	fn zone_mut(&mut self, idx : usize)->&mut Zone {
+		assert!(idx < self.zone_num, "no such zone: {}", idx);
+		unsafe {&mut *self.zone.add(idx)}
+	}
+
+	fn init_page(&mut self, rev_num : usize) {
+		for i in 0..self.zone_num {
+			for page in self.zone_mut(i).seg.pages_mut().iter_mut() {
+				page.free();
+			}
+		}
+		let zone = self.zone_mut(ZoneId::KERNEL.val());
+		let ptr = zone.seg.pages_mut();
+		// 页面数组所在的页面同保留页面，永不释放
+		for page in &mut ptr[..rev_num] {
+			page.reserve();
+		}
+		zone.free_num -= rev_num;
+	}
+
+	/// ### 移动 idx 与 idx + 1 号区域之间的边界
+	/// 跨过的页面必须空闲，up 为真时边界上移，低处的区域增大
+	fn move_boundary(&mut self, idx : usize, num : usize, up : bool) {
+		assert!(idx + 1 < self.zone_num);
+		let size = num * self.page_size;
+		let (low, high) = unsafe {
+			(&mut *self.zone.add(idx), &mut *self.zone.add(idx + 1))
+		};
+		if up {
+			low.seg.page_num += num;
+			low.add_free(num);
+			high.seg.start += size;
+			high.seg.page = unsafe {high.seg.page.add(num)};
+			high.seg.page_num -= num;
+			high.free_num -= num;
+			high.check_low();
+		}
+		else {
+			low.seg.page_num -= num;
+			low.free_num -= num;
+			low.check_low();
+			high.seg.start -= size;
+			high.seg.page = unsafe {high.seg.page.sub(num)};
+			high.seg.page_num += num;
+			high.add_free(num);
+		}
+	}
+
+	/// ### 从相邻区域取得页面
+	/// 区域 idx 在与 other 相邻的一端需要 num 个连续的空闲页面
+	/// 只移动初始的段，热插入的段不参与
+	fn borrow_from(&mut self, idx : usize, other : usize, num : usize)->bool {
+		if idx == ZoneId::DMA.val() || other == ZoneId::DMA.val() {
+			return false;
+		}
+		let up = other > idx;
+		let (low, high) = if up { (idx, other) } else { (other, idx) };
+		// 低处区域末尾、高处区域开头的空闲页面数
+		let tail = self.zone(low).seg.pages().iter().rev().take_while(|p| p.is_free()).count();
+		let head = self.zone(high).seg.pages().iter().take_while(|p| p.is_free()).count();
+		let (edge, other_edge) = if up { (tail, head) } else { (head, tail) };
+		if edge >= num {
+			return false;
+		}
+		let move_num = num - edge;
+		let other = self.zone(other);
+		// 不能让另一区域低于 min 水位
+		if other_edge < move_num || other.free_num - move_num < other.watermark.min {
+			return false;
 		}
+		self.move_boundary(low, move_num, up);
+		true
 	}
 
-	fn init_page(&mut self) {
-		let rev_num = (self.total_num + self.page_size - 1) / self.page_size;
-		let ptr = &mut self.kernel_page;
-		for i in 0..rev_num {
-			ptr[i].take();
+	/// ### addr 开始的 num 个页面及其所在区域
+	/// addr 需按页面对齐，范围需在同一段内
+	fn pages_at(&mut self, addr : *mut u8, num : usize)->Option<(usize, &mut [Page])> {
+		let page_size = self.page_size;
+		if num == 0 || !(addr as usize).is_multiple_of(page_size) {
+			return None;
 		}
-		for i in rev_num..self.kernel_page_num {
-			ptr[i].free();
+		let (zone, seg) = self.find(addr as usize)?;
+		let seg = unsafe {&mut *seg};
+		let idx = (addr as usize - seg.start) / page_size;
+		if idx + num > seg.page_num {
+			return None;
 		}
-		let ptr = &mut self.user_page;
-		for i in 0..self.user_page_num {
-			ptr[i].free();
+		Some((zone, &mut seg.pages_mut()[idx..idx + num]))
+	}
+
+	/// ### 对 addr 开始的一段已分配页面逐个操作
+	fn for_each_in_run(&mut self, addr : *mut u8, mut f : impl FnMut(&mut Page)) {
+		let (_, seg, mut idx) = self.locate(addr);
+		let ptr = seg.pages_mut();
+		assert!(!ptr[idx].is_free());
+		while !ptr[idx].is_end() {
+			f(&mut ptr[idx]);
+			idx += 1;
+		}
+		f(&mut ptr[idx]);
+	}
+
+	/// ### 将地址范围按页面向外取整
+	fn page_bound(&self, start : usize, end : usize)->(usize, usize) {
+		let page_size = self.page_size;
+		(start / page_size * page_size, end.saturating_add(page_size - 1) / page_size * page_size)
+	}
+
+	/// ### 找到包含地址的区域及段
+	fn find(&self, addr : usize)->Option<(usize, *mut Segment)> {
+		for i in 0..self.zone_num {
+			let mut seg = unsafe {addr_of_mut!((*self.zone.add(i)).seg)};
+			while !seg.is_null() {
+				if unsafe {(*seg).is_contain(addr)} {
+					return Some((i, seg));
+				}
+				seg = unsafe {(*seg).next};
+			}
+		}
+		None
+	}
+
+	/// ### 找到地址所在的区域、段及页面下标
+	fn locate(&mut self, addr : *mut u8)->(usize, &mut Segment, usize) {
+		let addr = addr as usize;
+		match self.find(addr) {
+			Some((zone, seg)) => {
+				let seg = unsafe {&mut *seg};
+				let idx = (addr - seg.start) / self.page_size;
+				(zone, seg, idx)
+			}
+			None => panic!("page out of range: {:x}, start {:x} end {:x}",
+				addr, self.zone(0).seg.start, self.memory_end),
 		}
 	}
 }
 
 impl PageOp for PageManager {
 	fn clone(&self) -> Self {
-		let kernel_page;
-		let user_page;
-		unsafe {
-			let t = self as *const Self as *mut Self;
-			kernel_page = &mut *((*t).kernel_page.as_ref() as *const [Page] as *mut [Page]);
-			user_page = &mut *((*t).user_page.as_ref() as *const [Page] as *mut [Page]);
-		}
         Self {
-            kernel_page : kernel_page,
-            kernel_page_num: self.kernel_page_num,
-            user_page : user_page,
-			kernel_start : self.kernel_start,
-            user_page_num: self.user_page_num,
-            user_start: self.user_start,
-            total_num: self.total_num,
+            zone : self.zone,
+            zone_num : self.zone_num,
             memory_end: self.memory_end,
             page_size: self.page_size,
 		}
     }
 
-    fn new(kmem_start : usize, umem_start : usize,
+    fn meta_size(page_num : usize, zone_num : usize)->usize {
+		size_of::<Zone>() * zone_num + page_num * size_of::<Page>()
+    }
+
+    fn new(mem_start : usize, zone_start : &[usize], reserved : &[Range<usize>],
 			total_mem : usize, page_size : usize)->Self {
-		let kmem_start = (kmem_start + page_size - 1) / page_size * page_size;
-		let umem_start = (umem_start + page_size - 1) / page_size * page_size;
-		let total_num = (total_mem - kmem_start) / page_size;
-		let kernel_page_num = (umem_start - kmem_start) / page_size;
-		let kernel_page = slice_from_raw_parts(kmem_start as *mut Page,
-										kernel_page_num) as *mut [Page];
-		let kernel_page = unsafe{&mut *(kernel_page)};
-		let user_page = slice_from_raw_parts(
-			(kmem_start + kernel_page_num) as *mut Page,
-			total_num - kernel_page_num) as *mut [Page];
-		let user_page = unsafe{&mut *(user_page)};
-		
+		let zone_num = zone_start.len() + 1;
+		assert!(zone_num > ZoneId::KERNEL.val() && zone_num <= ZONE_NUM, "bad zone number: {}", zone_num);
+		let mem_start = mem_start.div_ceil(page_size) * page_size;
+		let total_num = (total_mem - mem_start) / page_size;
+		// 区域信息与页面数组放在内核区域开头
+		let kernel_start = zone_start[ZoneId::KERNEL.val() - 1].div_ceil(page_size) * page_size;
+		let zone = kernel_start as *mut Zone;
+		let page = (kernel_start + size_of::<Zone>() * zone_num) as *mut Page;
+		let rev_num = Self::meta_size(total_num, zone_num).div_ceil(page_size);
+		// 写入页面数组之前检查，否则固件的内容已被覆盖
+		let meta_end = kernel_start + rev_num * page_size;
+		for range in reserved {
+			assert!(range.end <= kernel_start || range.start >= meta_end,
+				"reserved range {:x}..{:x} overlaps page array", range.start, range.end);
+		}
+		let mut start = mem_start;
+		let mut page_idx = 0;
+		// 最后一个区域到内存末尾为止
+		let ends = zone_start.iter()
+			.map(|s| s.div_ceil(page_size) * page_size)
+			.chain(core::iter::once(mem_start + total_num * page_size));
+		for (i, end) in ends.enumerate() {
+			assert!(end >= start, "zone {} starts below the previous zone", i + 1);
+			let page_num = (end - start) / page_size;
+			unsafe {
+				zone.add(i).write(Zone::new(ZoneId(i), start, page_num,
+					page_size, page.add(page_idx)));
+			}
+			start = end;
+			page_idx += page_num;
+		}
+		assert!(rev_num <= unsafe {(*zone.add(ZoneId::KERNEL.val())).seg.page_num},
+			"kernel zone too small for page array");
+
 		let mut rt = Self {
-		    kernel_page : kernel_page,
-		    kernel_page_num,
-			kernel_start : kmem_start,
-		    user_page : user_page,
-		    user_page_num: total_num - kernel_page_num,
-			user_start : umem_start,
-		    total_num,
+			zone,
+			zone_num,
 			memory_end : total_mem,
 		    page_size,
 		};
-		rt.init_page();
+		rt.init_page(rev_num);
+		for range in reserved {
+			rt.reserve_range(range.clone());
+		}
 		rt
     }
 
-    fn alloc_kernel_page(&mut self, num : usize)->Option<*mut u8> {
-		assert!(num > 0);
-		let ptr = &mut self.kernel_page;
+    fn alloc_page(&mut self, num : usize, zone : ZoneId)->Option<*mut u8> {
+		self.zone_mut(zone.val()).alloc(num, false)
+    }
+
+    fn alloc_kernel_page_critical(&mut self, num : usize)->Option<*mut u8> {
+		self.zone_mut(ZoneId::KERNEL.val()).alloc(num, true)
+    }
+
+    fn alloc_dma_page(&mut self, num : usize, max_addr : usize, align : usize)->Option<*mut u8> {
+		if !is_valid_align(align, self.page_size) {
+			return None;
+		}
+		self.zone_mut(ZoneId::DMA.val()).alloc_in(num, false, align, max_addr)
+    }
+
+    fn alloc_pages_aligned(&mut self, num : usize, align : usize, zone : ZoneId)->Option<*mut u8> {
+		if !is_valid_align(align, self.page_size) {
+			return None;
+		}
+		self.zone_mut(zone.val()).alloc_in(num, false, align, usize::MAX)
+    }
+
+    fn claim_pages(&mut self, addr : *mut u8, num : usize)->bool {
+		let (zone, ptr) = match self.pages_at(addr, num) {
+			Some(rt) => rt,
+			None => return false,
+		};
+		if !ptr.iter().all(Page::is_free) {
+			return false;
+		}
+		for page in ptr.iter_mut() {
+			page.take();
+		}
+		ptr[num - 1].end();
+		let zone = self.zone_mut(zone);
+		zone.free_num -= num;
+		zone.check_low();
+		true
+    }
+
+    fn alloc_huge_page(&mut self, order : usize, zone : ZoneId)->Option<*mut u8> {
+		let num = huge_page_num(order, self.page_size)?;
+		let rt = self.alloc_pages_aligned(num, num * self.page_size, zone)?;
+		self.set_flag(rt, PageBit::Huge);
+		Some(rt)
+    }
+
+    fn free_page(&mut self, addr : *mut u8) {
+		self.put_page(addr);
+    }
+
+    fn get_page(&mut self, addr : *mut u8) {
+		let (_, seg, idx) = self.locate(addr);
+		assert!(seg.is_head(idx), "get page inside a run {:x}", addr as usize);
+		let page = &mut seg.pages_mut()[idx];
+		assert!(page.is_busy() && page.count > 0, "get free page {:x}", addr as usize);
+		page.count += 1;
+    }
+
+    fn put_page(&mut self, addr : *mut u8)->bool {
+		let (zone, seg, mut idx) = self.locate(addr);
+		assert!(seg.is_head(idx), "free page inside a run {:x}", addr as usize);
+		let pinned = seg.is_run_pinned(idx);
+		let ptr = seg.pages_mut();
+		assert!(!ptr[idx].is_reserved(), "free reserved page {:x}", addr as usize);
+		assert!(!ptr[idx].is_offline(), "free offline page {:x}", addr as usize);
+		assert!(ptr[idx].count > 0, "free free page {:x}", addr as usize);
+		ptr[idx].count -= 1;
+		// 固定的页面放弃最后一个引用后计数为 0，解除全部固定时再释放
+		if ptr[idx].count == 0 && pinned {
+			return false;
+		}
+		if ptr[idx].count > 0 {
+			return false;
+		}
+		let mut cnt = 1;
+		while !ptr[idx].is_end() {
+			assert!(!ptr[idx].is_free());
+			ptr[idx].free();
+			idx += 1;
+			cnt += 1;
+		}
+		assert!(!ptr[idx].is_free());
+		ptr[idx].free();
+		self.zone_mut(zone).add_free(cnt);
+		true
+    }
+
+    fn page_count(&self, addr : *mut u8)->usize {
+		let (_, seg) = self.find(addr as usize).expect("page out of range");
+		let seg = unsafe {&*seg};
+		seg.pages()[(addr as usize - seg.start) / self.page_size].count as usize
+    }
+
+    fn set_tag(&mut self, addr : *mut u8, tag : Tag) {
+		self.for_each_in_run(addr, |page| page.tag = tag.val());
+    }
+
+    fn tag_page_num(&self, tag : Tag)->usize {
 		let mut cnt = 0;
-		for i in 0..self.kernel_page_num {
-			if ptr[i].is_free() {
-				cnt += 1;
+		for i in 0..self.zone_num {
+			cnt += self.zone(i).segments().flat_map(Segment::pages)
+				.filter(|p| !p.is_free() && p.tag == tag.val()).count();
+		}
+		cnt
+    }
+
+    fn set_owner(&mut self, addr : *mut u8, owner : OwnerId) {
+		self.for_each_in_run(addr, |page| page.owner = owner);
+    }
+
+    fn pin_pages(&mut self, addr : *mut u8, num : usize)->bool {
+		let ptr = match self.pages_at(addr, num) {
+			Some((_, ptr)) => ptr,
+			None => return false,
+		};
+		if !ptr.iter().all(Page::is_busy) || ptr[..num - 1].iter().any(Page::is_end) {
+			return false;
+		}
+		for page in ptr.iter_mut() {
+			page.pin();
+		}
+		true
+    }
+
+    fn unpin_pages(&mut self, addr : *mut u8, num : usize)->bool {
+		let ptr = match self.pages_at(addr, num) {
+			Some((_, ptr)) => ptr,
+			None => panic!("unpin pages out of range {:x}", addr as usize),
+		};
+		for page in ptr.iter_mut() {
+			page.unpin();
+		}
+		// 固定期间放弃了最后一个引用的一段页面，解除全部固定后释放
+		let (zone, seg, mut idx) = self.locate(addr);
+		while !seg.is_head(idx) {
+			idx -= 1;
+		}
+		if seg.pages()[idx].count > 0 || seg.is_run_pinned(idx) {
+			return false;
+		}
+		let run = seg.run_len(idx);
+		seg.pages_mut()[idx..idx + run].iter_mut().for_each(Page::free);
+		self.zone_mut(zone).add_free(run);
+		true
+    }
+
+    fn pinned_page_num(&self, zone : ZoneId)->usize {
+		self.zone(zone.val()).segments().flat_map(Segment::pages)
+			.filter(|p| p.is_pinned()).count()
+    }
+
+    fn can_move(&self, addr : *mut u8)->bool {
+		let (_, seg) = match self.find(addr as usize) {
+			Some(rt) => rt,
+			None => return false,
+		};
+		let seg = unsafe {&*seg};
+		let idx = (addr as usize - seg.start) / self.page_size;
+		let page = &seg.pages()[idx];
+		seg.is_head(idx) && page.is_busy() && page.count == 1 && !seg.is_run_pinned(idx)
+    }
+
+    fn largest_free_run(&self, zone : ZoneId)->usize {
+		let mut rt = 0;
+		self.zone(zone.val()).for_each_free_run(|len| rt = rt.max(len));
+		rt
+    }
+
+    fn free_run_histogram(&self, zone : ZoneId)->[usize; FREE_RUN_ORDER] {
+		let mut rt = [0; FREE_RUN_ORDER];
+		self.zone(zone.val()).for_each_free_run(|len| {
+			let order = (usize::BITS - 1 - len.leading_zeros()) as usize;
+			rt[order.min(FREE_RUN_ORDER - 1)] += 1;
+		});
+		rt
+    }
+
+    fn fragmentation_index(&self, zone : ZoneId)->usize {
+		let free = self.zone(zone.val()).free_num;
+		if free == 0 {
+			return 0;
+		}
+		(free - self.largest_free_run(zone)) * 100 / free
+    }
+
+    fn movable_window(&self, num : usize, align : usize, max_addr : usize, zone : ZoneId,
+			is_movable : impl Fn(*mut u8)->bool)->Option<*mut u8> {
+		self.zone(zone.val()).segments()
+			.find_map(|seg| seg.movable_window(num, align, max_addr, &is_movable))
+    }
+
+    fn set_flag(&mut self, addr : *mut u8, bit : PageBit) {
+		assert!(bit.is_usage(), "{:?} is managed by the page manager", bit);
+		self.for_each_in_run(addr, |page| page.flag |= bit.val());
+    }
+
+    fn clear_flag(&mut self, addr : *mut u8, bit : PageBit) {
+		assert!(bit.is_usage(), "{:?} is managed by the page manager", bit);
+		self.for_each_in_run(addr, |page| page.flag &= !bit.val());
+    }
+
+    fn page_info(&self, addr : *mut u8)->Option<PageInfo> {
+		let (zone, seg) = self.find(addr as usize)?;
+		let seg = unsafe {&*seg};
+		let ptr = seg.pages();
+		let idx = (addr as usize - seg.start) / self.page_size;
+		// 保留页面每页自成一段，走下面的查找同样得到其自身
+		let (mut head, mut end) = (idx, idx);
+		if !ptr[idx].is_free() {
+			while !seg.is_head(head) {
+				head -= 1;
 			}
-			else {
-				cnt = 0;
+			while end + 1 < seg.page_num && !ptr[end].is_end() {
+				end += 1;
 			}
-			if cnt >= num {
-				for idx in i + 1 - cnt..=i {
-					ptr[idx].take();
+		}
+		let page = &ptr[idx];
+		Some(PageInfo {
+			flag : page.flag,
+			zone : ZoneId(zone),
+			owner : page.owner,
+			tag : Tag(page.tag),
+			head : (seg.start + head * self.page_size) as *mut u8,
+			page_num : end - head + 1,
+			count : ptr[head].count as usize,
+		})
+    }
+
+    fn free_all_owned_by(&mut self, owner : OwnerId)->usize {
+		assert!(owner != NO_OWNER);
+		let mut total = 0;
+		for i in 0..self.zone_num {
+			let zone = self.zone_mut(i);
+			let mut cnt = 0;
+			zone.for_each_segment(|seg| cnt += seg.put_owned(owner));
+			zone.add_free(cnt);
+			total += cnt;
+		}
+		total
+    }
+
+    fn owned_page_num(&self, owner : OwnerId, zone : ZoneId)->usize {
+		self.zone(zone.val()).segments().flat_map(Segment::pages)
+			.filter(|p| p.is_busy() && p.owner == owner).count()
+    }
+
+    fn free_page_num(&self, zone : ZoneId)->usize {
+		self.zone(zone.val()).free_num
+    }
+
+    fn set_watermark(&mut self, zone : ZoneId, mark : Watermark)->Result<(), MemoryError> {
+		if zone.val() >= self.zone_num {
+			return Err(MemoryError::InvalidZone);
+		}
+		if !mark.is_valid() {
+			return Err(MemoryError::InvalidWatermark);
+		}
+		let zone = self.zone_mut(zone.val());
+		zone.watermark = mark;
+		zone.add_free(0);
+		zone.check_low();
+		Ok(())
+    }
+
+    fn is_low_memory(&self, zone : ZoneId)->bool {
+		self.zone(zone.val()).is_low
+    }
+
+    fn set_low_memory_handler(&mut self, handler : Option<LowMemoryHandler>) {
+		for i in 0..self.zone_num {
+			self.zone_mut(i).low_handler = handler;
+		}
+    }
+
+    fn reserve_range(&mut self, range : Range<usize>)->bool {
+		let (start, end) = self.page_bound(range.start, range.end);
+		// 先检查，避免只保留了一部分
+		for i in 0..self.zone_num {
+			for seg in self.zone(i).segments() {
+				if seg.pages()[seg.clip(start, end)].iter()
+						.any(|p| !p.is_free() && !p.is_reserved()) {
+					return false;
 				}
-				ptr[i].end();
-				let addr = ((i + 1 - cnt) * self.page_size +
-					self.kernel_start) as *mut u8;
-				self.clear(addr, num);
-				return Some(addr);
 			}
 		}
-		None
+		for i in 0..self.zone_num {
+			let zone = self.zone_mut(i);
+			let mut cnt = 0;
+			zone.for_each_segment(|seg| {
+				let range = seg.clip(start, end);
+				for page in seg.pages_mut()[range].iter_mut() {
+					if page.is_free() {
+						page.reserve();
+						cnt += 1;
+					}
+				}
+			});
+			zone.free_num -= cnt;
+			zone.check_low();
+		}
+		true
     }
 
-    fn alloc_user_page(&mut self, num : usize)->Option<*mut u8> {
-		assert!(num > 0);
-		let ptr = &mut self.user_page;
-		let mut cnt = 0;
-		for i in 0..self.user_page_num {
-			if ptr[i].is_free() {
-				cnt += 1;
-			}
-			else {
-				cnt = 0;
-			}
-			if cnt >= num {
-				for idx in i + 1 - cnt..=i {
-					ptr[idx].take();
+    fn offline_range(&mut self, start : usize, len : usize)->Result<usize, *mut u8> {
+		let (start, end) = self.page_bound(start, start.saturating_add(len));
+		for i in 0..self.zone_num {
+			for seg in self.zone(i).segments() {
+				let range = seg.clip(start, end);
+				if let Some(idx) = range.clone().find(|idx| seg.pages()[*idx].is_busy()) {
+					return Err((seg.start + idx * self.page_size) as *mut u8);
 				}
-				ptr[i].end();
-				let addr = ((i + 1 - cnt) * self.page_size + self.user_start) as *mut u8;
-				self.clear(addr, num);
-				return Some(addr);
 			}
 		}
-		panic!("cnt {} num {} user num {}", cnt, num, self.user_page_num);
+		let mut total = 0;
+		for i in 0..self.zone_num {
+			let zone = self.zone_mut(i);
+			let mut cnt = 0;
+			zone.for_each_segment(|seg| {
+				let range = seg.clip(start, end);
+				for page in seg.pages_mut()[range].iter_mut() {
+					if page.is_free() {
+						page.offline();
+						cnt += 1;
+					}
+				}
+			});
+			zone.free_num -= cnt;
+			zone.check_low();
+			total += cnt;
+		}
+		Ok(total)
     }
 
-    fn free_page(&mut self, addr : *mut u8) {
-		if addr as usize >= self.kernel_start && (addr as usize) < self.user_start {
-			let mut idx = (addr as usize - self.kernel_start) / self.page_size;
-			let ptr = &mut self.kernel_page;
-			while !ptr[idx].is_end() {
-				assert!(!ptr[idx].is_free());
-				ptr[idx].free();
-				idx += 1;
-			}
-			assert!(!ptr[idx].is_free());
-			ptr[idx].free();
+    fn online_range(&mut self, start : usize, len : usize)->usize {
+		let (start, end) = self.page_bound(start, start.saturating_add(len));
+		let mut total = 0;
+		for i in 0..self.zone_num {
+			let zone = self.zone_mut(i);
+			let mut cnt = 0;
+			zone.for_each_segment(|seg| {
+				let range = seg.clip(start, end);
+				for page in seg.pages_mut()[range].iter_mut() {
+					if page.is_offline() {
+						page.free();
+						cnt += 1;
+					}
+				}
+			});
+			zone.add_free(cnt);
+			total += cnt;
+		}
+		total
+    }
+
+    fn add_region(&mut self, start : usize, len : usize, zone : ZoneId)->Result<(), MemoryError> {
+		let page_size = self.page_size;
+		let end = start.checked_add(len).ok_or(MemoryError::InvalidRegion)? / page_size * page_size;
+		let start = start.div_ceil(page_size) * page_size;
+		if zone.val() >= self.zone_num || start >= end {
+			return Err(MemoryError::InvalidRegion);
 		}
-		else if addr as usize >= self.user_start && (addr as usize) < self.memory_end {
-			let mut idx = (addr as usize - self.user_start) / self.page_size;
-			let ptr = &mut self.user_page;
-			while !ptr[idx].is_end() {
-				assert!(!ptr[idx].is_free());
-				ptr[idx].free();
-				idx += 1;
+		for i in 0..self.zone_num {
+			if self.zone(i).segments().any(|seg| seg.is_overlap(start, end)) {
+				return Err(MemoryError::InvalidRegion);
 			}
-			assert!(!ptr[idx].is_free());
-			ptr[idx].free();
 		}
-		else {
-			panic!("page out of range: {:x}, kernel {:x}, user {:x} end {:x}",
-				addr as usize, self.kernel_start, self.user_start, self.memory_end);
+		let total_num = (end - start) / page_size;
+		let meta_num = (size_of::<Segment>() + total_num * size_of::<Page>()).div_ceil(page_size);
+		if meta_num >= total_num {
+			return Err(MemoryError::InvalidRegion);
+		}
+		let seg = start as *mut Segment;
+		let page = (start + size_of::<Segment>()) as *mut Page;
+		unsafe {
+			let mut s = Segment::new(start + meta_num * page_size, total_num - meta_num, page_size, page);
+			s.meta = meta_num * page_size;
+			seg.write(s);
+			for page in (*seg).pages_mut().iter_mut() {
+				page.free();
+			}
 		}
+		self.zone_mut(zone.val()).append(seg);
+		Ok(())
+    }
+
+    fn zone_num(&self)->usize {
+		self.zone_num
+    }
+
+    fn zone_of(&self, addr : *mut u8)->Option<ZoneId> {
+		self.find(addr as usize).map(|(zone, _)| ZoneId(zone))
+    }
+
+    fn expand_zone(&mut self, zone : ZoneId, num : usize)->bool {
+		let idx = zone.val();
+		// 先向上找高处的区域，再向下
+		(idx + 1 < self.zone_num && self.borrow_from(idx, idx + 1, num)) ||
+			(idx > 0 && self.borrow_from(idx, idx - 1, num))
     }
 
     fn page_size(&self)->usize {
@@ -180,9 +649,9 @@ impl PageOp for PageManager {
 
     fn print(&self) {
 		let mut _cnt = 0;
-		let ptr = &self.kernel_page;
-		for i in 0..self.kernel_page_num {
-			if !ptr[i].is_free() {
+		let ptr = self.zone(ZoneId::KERNEL.val()).seg.pages();
+		for page in ptr.iter() {
+			if !page.is_free() {
 				_cnt += 1;
 			}
 			else {
@@ -192,14 +661,336 @@ impl PageOp for PageManager {
     }
 }
 
+/// ## 内存区域
+/// 由初始的一段连续物理页面及热插入的段组成，空闲页面与水位按区域统计
+struct Zone {
+	id : ZoneId,
+	seg : Segment,
+	free_num : usize,
+	watermark : Watermark,
+	/// 空闲页面低于 low 水位时置位，回到 high 水位以上后清除
+	is_low : bool,
+	low_handler : Option<LowMemoryHandler>,
+}
+
+impl Zone {
+	fn new(id : ZoneId, start : usize, page_num : usize,
+			page_size : usize, page : *mut Page)->Self {
+		Self {
+			id,
+			seg : Segment::new(start, page_num, page_size, page),
+			free_num : page_num,
+			watermark : Watermark::default(),
+			is_low : false,
+			low_handler : None,
+		}
+	}
+
+	fn segments(&self)->Segments<'_> {
+		Segments {
+			seg : &self.seg,
+			_marker : PhantomData,
+		}
+	}
+
+	fn for_each_segment(&mut self, mut f : impl FnMut(&mut Segment)) {
+		let mut seg = addr_of_mut!(self.seg);
+		while !seg.is_null() {
+			unsafe {
+				f(&mut *seg);
+				seg = (*seg).next;
+			}
+		}
+	}
+
+	/// ### 依次给出每一段连续空闲页面的长度
+	fn for_each_free_run(&self, mut f : impl FnMut(usize)) {
+		for seg in self.segments() {
+			let mut len = 0;
+			for page in seg.pages() {
+				if page.is_free() {
+					len += 1;
+				}
+				else if len > 0 {
+					f(len);
+					len = 0;
+				}
+			}
+			if len > 0 {
+				f(len);
+			}
+		}
+	}
+
+	/// ### 将热插入的段接到末尾
+	fn append(&mut self, seg : *mut Segment) {
+		let mut last = addr_of_mut!(self.seg);
+		unsafe {
+			while !(*last).next.is_null() {
+				last = (*last).next;
+			}
+			(*last).next = seg;
+			self.add_free((*seg).page_num);
+		}
+	}
+
+	/// ### 申请连续页面
+	/// 非紧急申请不能使空闲页面低于 min 水位
+	fn alloc(&mut self, num : usize, critical : bool)->Option<*mut u8> {
+		self.alloc_in(num, critical, 1, usize::MAX)
+	}
+
+	/// ### 申请满足地址限制的连续页面
+	/// 起始地址按 align 对齐，最后一个字节的地址不超过 max_addr
+	fn alloc_in(&mut self, num : usize, critical : bool,
+			align : usize, max_addr : usize)->Option<*mut u8> {
+		assert!(align > 0);
+		if num == 0 || num > self.free_num || (!critical && self.free_num - num < self.watermark.min) {
+			return None;
+		}
+		let mut rt = None;
+		self.for_each_segment(|seg| {
+			if rt.is_none() {
+				rt = seg.alloc(num, align, max_addr);
+			}
+		});
+		if rt.is_some() {
+			self.free_num -= num;
+			self.check_low();
+		}
+		rt
+	}
+
+	fn add_free(&mut self, num : usize) {
+		self.free_num += num;
+		if self.is_low && self.free_num >= self.watermark.high {
+			self.is_low = false;
+		}
+	}
+
+	/// ### 空闲页面跌破 low 水位时标记并通知
+	fn check_low(&mut self) {
+		if !self.is_low && self.free_num < self.watermark.low {
+			self.is_low = true;
+			if let Some(handler) = self.low_handler {
+				handler(self.id);
+			}
+		}
+	}
+}
+
+/// ## 连续的物理页面及其页面数组
+/// 热插入的段把自身和页面数组放在所在内存的开头
+struct Segment {
+	start : usize,
+	page_num : usize,
+	page_size : usize,
+	page : *mut Page,
+	/// 段之前存放元数据的字节数
+	meta : usize,
+	next : *mut Segment,
+}
+
+impl Segment {
+	fn new(start : usize, page_num : usize, page_size : usize, page : *mut Page)->Self {
+		Self {
+			start,
+			page_num,
+			page_size,
+			page,
+			meta : 0,
+			next : null_mut(),
+		}
+	}
+
+	fn pages(&self)->&[Page] {
+		unsafe {from_raw_parts(self.page, self.page_num)}
+	}
+
+	fn pages_mut(&mut self)->&mut [Page] {
+		unsafe {from_raw_parts_mut(self.page, self.page_num)}
+	}
+
+	fn end(&self)->usize {
+		self.start + self.page_num * self.page_size
+	}
+
+	fn is_contain(&self, addr : usize)->bool {
+		addr >= self.start && addr < self.end()
+	}
+
+	/// ### 是否与 start..end 重叠，包括元数据
+	fn is_overlap(&self, start : usize, end : usize)->bool {
+		start < self.end() && end > self.start - self.meta
+	}
+
+	/// ### 是否为一段已分配页面的第一页
+	fn is_head(&self, idx : usize)->bool {
+		idx == 0 || self.pages()[idx - 1].is_free() || self.pages()[idx - 1].is_end()
+	}
+
+	/// ### idx 开始的一段页面的页面数，空闲页面为 1
+	fn run_len(&self, idx : usize)->usize {
+		let ptr = self.pages();
+		if ptr[idx].is_free() {
+			return 1;
+		}
+		ptr[idx..].iter().position(Page::is_end).unwrap() + 1
+	}
+
+	/// ### 属于 owner 的每段页面放弃一次引用，返回释放的页面数
+	/// 引用计数降为 0 时释放，否则留给其余的使用者，不再属于 owner
+	/// 固定的页面放弃最后一个引用后仍属于 owner，解除固定时释放
+	fn put_owned(&mut self, owner : OwnerId)->usize {
+		let mut cnt = 0;
+		let mut idx = 0;
+		while idx < self.page_num {
+			let run = self.run_len(idx);
+			let ptr = &mut self.pages_mut()[idx..idx + run];
+			let pinned = ptr.iter().any(Page::is_pinned);
+			// 计数为 0 的一段页面正等待解除固定，不再放弃引用
+			if ptr[0].is_busy() && ptr[0].owner == owner && ptr[0].count > 0 {
+				ptr[0].count -= 1;
+				if ptr[0].count == 0 && !pinned {
+					ptr.iter_mut().for_each(Page::free);
+					cnt += run;
+				}
+				else if ptr[0].count > 0 {
+					ptr.iter_mut().for_each(|page| page.owner = NO_OWNER);
+				}
+			}
+			idx += run;
+		}
+		cnt
+	}
+
+	/// ### 从 idx 开始的一段页面中是否有固定的页面
+	fn is_run_pinned(&self, idx : usize)->bool {
+		let run = self.run_len(idx);
+		self.pages()[idx..idx + run].iter().any(Page::is_pinned)
+	}
+
+	/// ### 找到 num 个连续页面，其中只有空闲页面和可移动的页面
+	/// 可移动的一段页面由 is_movable 按第一页的地址判断，不能有固定的页面，也不能被共享
+	/// 地址限制同 alloc
+	fn movable_window(&self, num : usize, align : usize, max_addr : usize,
+			is_movable : &impl Fn(*mut u8)->bool)->Option<*mut u8> {
+		let ptr = self.pages();
+		let mut i = 0;
+		'window: while i + num <= self.page_num {
+			let addr = self.start + i * self.page_size;
+			if addr + num * self.page_size - 1 > max_addr {
+				break;
+			}
+			let next = addr.div_ceil(align) * align;
+			if next != addr {
+				i = (next - self.start).div_ceil(self.page_size);
+				continue;
+			}
+			let mut j = i;
+			while j < i + num {
+				if ptr[j].is_free() {
+					j += 1;
+					continue;
+				}
+				let mut head = j;
+				while !self.is_head(head) {
+					head -= 1;
+				}
+				let mut end = j;
+				while !ptr[end].is_end() {
+					end += 1;
+				}
+				if !ptr[j].is_busy() || ptr[head].count > 1 || ptr[head..=end].iter().any(Page::is_pinned) ||
+						!is_movable((self.start + head * self.page_size) as *mut u8) {
+					i = end + 1;
+					continue 'window;
+				}
+				j = end + 1;
+			}
+			return Some((self.start + i * self.page_size) as *mut u8);
+		}
+		None
+	}
+
+	/// ### 地址范围与本段相交部分的页面下标
+	fn clip(&self, start : usize, end : usize)->Range<usize> {
+		let start = start.max(self.start);
+		let end = end.min(self.end());
+		if start >= end {
+			return 0..0;
+		}
+		(start - self.start) / self.page_size..(end - self.start) / self.page_size
+	}
+
+	fn alloc(&mut self, num : usize, align : usize, max_addr : usize)->Option<*mut u8> {
+		let page_num = self.page_num;
+		let page_size = self.page_size;
+		let start = self.start;
+		let ptr = self.pages_mut();
+		let mut i = 0;
+		while i + num <= page_num {
+			let addr = start + i * page_size;
+			if addr + num * page_size - 1 > max_addr {
+				break;
+			}
+			if !addr.is_multiple_of(align) {
+				let next = addr.div_ceil(align) * align;
+				i = (next - start).div_ceil(page_size);
+				continue;
+			}
+			// 跳过已占用的页面继续查找
+			if let Some(taken) = (i..i + num).rev().find(|idx| !ptr[*idx].is_free()) {
+				i = taken + 1;
+				continue;
+			}
+			for page in &mut ptr[i..i + num] {
+				page.take();
+			}
+			ptr[i + num - 1].end();
+			let addr = addr as *mut u8;
+			unsafe {
+				addr.write_bytes(0, num * page_size);
+			}
+			return Some(addr);
+		}
+		None
+	}
+}
+
+struct Segments<'a> {
+	seg : *const Segment,
+	_marker : PhantomData<&'a Segment>,
+}
+
+impl<'a> Iterator for Segments<'a> {
+	type Item = &'a Segment;
+
+	fn next(&mut self)->Option<Self::Item> {
+		if self.seg.is_null() {
+			return None;
+		}
+		let rt = unsafe {&*self.seg};
+		self.seg = rt.next;
+		Some(rt)
+	}
+}
+
 #[derive(Copy, Clone)]
 pub struct Page{
-	pub flag : u8
+	pub flag : u16,
+	pub tag : u8,
+	/// 固定次数，不为 0 时不能释放、移动
+	pub pin : u8,
+	/// 引用计数，只在一段页面的第一页上有意义
+	pub count : u32,
+	pub owner : OwnerId,
 }
 
 impl Page {
 	pub fn take(&mut self){
 		self.flag = PageBit::Taken.val();
+		self.count = 1;
 	}
 	pub fn end(&mut self){
 		self.flag |= PageBit::End.val();
@@ -209,21 +1000,445 @@ impl Page {
 	}
 	pub fn free(&mut self) {
 		self.flag = 0;
+		self.pin = 0;
+		self.count = 0;
+		self.tag = Tag::NONE.val();
+		self.owner = NO_OWNER;
 	}
 	pub fn is_end(&self)->bool {
 		self.flag & PageBit::End.val() != 0
 	}
+	/// 保留页面自成一段，永不释放
+	pub fn reserve(&mut self) {
+		self.flag = PageBit::Taken.val() | PageBit::End.val() | PageBit::Reserved.val();
+	}
+	pub fn is_reserved(&self)->bool {
+		self.flag & PageBit::Reserved.val() != 0
+	}
+	/// 下线的页面同样自成一段，上线后恢复空闲
+	pub fn offline(&mut self) {
+		self.flag = PageBit::Taken.val() | PageBit::End.val() | PageBit::Offline.val();
+	}
+	pub fn is_offline(&self)->bool {
+		self.flag & PageBit::Offline.val() != 0
+	}
+	pub fn pin(&mut self) {
+		assert!(self.pin < u8::MAX, "page pinned too many times");
+		self.pin += 1;
+		self.flag |= PageBit::Pinned.val();
+	}
+	pub fn unpin(&mut self) {
+		assert!(self.pin > 0, "unpin page not pinned");
+		self.pin -= 1;
+		if self.pin == 0 {
+			self.flag &= !PageBit::Pinned.val();
+		}
+	}
+	pub fn is_pinned(&self)->bool {
+		self.pin > 0
+	}
+	/// 已分配出去的页面
+	pub fn is_busy(&self)->bool {
+		!self.is_free() && !self.is_reserved() && !self.is_offline()
+	}
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum PageBit{
 	Taken = 1 << 0,
 	End = 1 << 1,
+	/// 固件、设备树、MMIO 等占用的页面
+	Reserved = 1 << 2,
+	/// 暂时下线，不参与分配
+	Offline = 1 << 3,
+	/// 以下为页面用途，整段页面一起设置
+	/// 自然对齐的大页
+	Huge = 1 << 4,
+	/// 设备正在访问，不能释放、移动
+	Pinned = 1 << 5,
+	/// 内容已被修改
+	Dirty = 1 << 6,
+	/// 对象缓存使用的页面
+	Slab = 1 << 7,
+	/// 堆内存池所在的页面
+	HeapPool = 1 << 8,
+	/// 页表
+	PageTable = 1 << 9,
 }
 
 impl PageBit {
-	pub const fn val(self) -> u8{
-		self as u8
+	pub const fn val(self) -> u16{
+		self as u16
+	}
+
+	/// 全部用途标志，迁移页面时一并复制
+	pub const USAGE : [PageBit; 5] = [PageBit::Huge, PageBit::Dirty, PageBit::Slab,
+		PageBit::HeapPool, PageBit::PageTable];
+
+	/// 可以由使用者设置、清除的标志，Pinned 由 pin_pages 计数维护
+	pub const fn is_usage(self)->bool {
+		self.val() >= PageBit::Huge.val() && !matches!(self, PageBit::Pinned)
 	}
 }
 
+/// ## 页面信息
+/// 调试时查询物理页面的用途
+#[derive(Copy, Clone, Debug)]
+pub struct PageInfo {
+	pub flag : u16,
+	pub zone : ZoneId,
+	pub owner : OwnerId,
+	pub tag : Tag,
+	/// 所在一段页面的起始地址及页面数，空闲页面、保留页面为其自身
+	pub head : *mut u8,
+	pub page_num : usize,
+	/// 所在一段页面的引用计数
+	pub count : usize,
+}
+
+impl PageInfo {
+	pub fn has(&self, bit : PageBit)->bool {
+		self.flag & bit.val() != 0
+	}
+
+	pub fn is_free(&self)->bool {
+		self.flag == 0
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::vec::Vec;
+
+	use crate::{MemoryOp, PageOp, config::PAGE_SIZE, error::MemoryError, testing::{Arena, Manager}, zone::ZoneId};
+	use super::{PageBit, PageManager, huge_page_num};
+
+	#[test]
+	fn reserved_range() {
+		let arena = Arena::new(1 << 20);
+		// 不按页面对齐的范围扩展到整页
+		let hole = arena.start() + 200 * PAGE_SIZE..arena.start() + 202 * PAGE_SIZE + 10;
+		let mut mgr = Manager::with_zones(arena.start(), &[32, 64],
+			core::slice::from_ref(&hole), PAGE_SIZE, arena.end());
+		let user = mgr.free_page_num(ZoneId::USER);
+		assert_eq!(user, 256 - 96 - 3);
+		// 之后保留跨区域的范围
+		let kernel = mgr.free_page_num(ZoneId::KERNEL);
+		let start = arena.start() + 94 * PAGE_SIZE;
+		assert!(mgr.reserve_range(start..start + 4 * PAGE_SIZE));
+		assert_eq!(mgr.free_page_num(ZoneId::KERNEL), kernel - 2);
+		assert_eq!(mgr.free_page_num(ZoneId::USER), user - 2);
+		// 已保留的部分可以再次保留
+		assert!(mgr.reserve_range(start..start + PAGE_SIZE));
+		let mut page = Vec::new();
+		while let Some(addr) = mgr.user_page(1) {
+			let addr_val = addr as usize;
+			assert!(!(hole.start..arena.start() + 203 * PAGE_SIZE).contains(&addr_val));
+			assert!(!(start..start + 4 * PAGE_SIZE).contains(&addr_val));
+			page.push(addr);
+		}
+		// 范围内有已分配的页面时不保留任何页面
+		let kernel = mgr.free_page_num(ZoneId::KERNEL);
+		let busy = page[0] as usize;
+		assert!(!mgr.reserve_range(busy - PAGE_SIZE..busy + PAGE_SIZE));
+		assert_eq!(mgr.free_page_num(ZoneId::KERNEL), kernel);
+		for addr in page {
+			mgr.free_page(addr);
+		}
+		assert_eq!(mgr.free_page_num(ZoneId::USER), user - 2);
+	}
+
+	#[test]
+	fn add_region() {
+		let arena = Arena::new(1 << 20);
+		let mut mgr = arena.manager(128);
+		let bank = Arena::new(256 << 10);
+		let start = bank.start();
+		// 与已管理的内存重叠、过小，或区域不存在
+		assert_eq!(mgr.add_region(arena.start() + PAGE_SIZE, 2 * PAGE_SIZE, ZoneId::USER),
+			Err(MemoryError::InvalidRegion));
+		assert_eq!(mgr.add_region(start, PAGE_SIZE, ZoneId::USER), Err(MemoryError::InvalidRegion));
+		assert_eq!(mgr.add_region(start, 256 << 10, ZoneId(5)), Err(MemoryError::InvalidRegion));
+		let free = mgr.free_page_num(ZoneId::USER);
+		mgr.add_region(start, 256 << 10, ZoneId::USER).unwrap();
+		// 开头的一页存放段与页面数组
+		assert_eq!(mgr.free_page_num(ZoneId::USER), free + 63);
+		assert_eq!(mgr.add_region(start + 2 * PAGE_SIZE, 4 * PAGE_SIZE, ZoneId::KERNEL),
+			Err(MemoryError::InvalidRegion));
+		let mut page = Vec::new();
+		while let Some(addr) = mgr.user_page(1) {
+			page.push(addr);
+		}
+		let in_bank = page.iter().filter(|addr| (start..bank.end()).contains(&(**addr as usize))).count();
+		assert_eq!(in_bank, 63);
+		// 新段的页面与原有页面一样管理
+		assert!(!mgr.reserve_range(start + PAGE_SIZE..start + 2 * PAGE_SIZE));
+		for addr in page {
+			mgr.free_page(addr);
+		}
+		// 原有的段占满后，连续的页面只能来自新段
+		let main_num = mgr.free_page_num(ZoneId::USER) - 63;
+		let main = mgr.user_page(main_num).unwrap();
+		let addr = mgr.user_page(60).unwrap();
+		assert!((start..bank.end()).contains(&(addr as usize)));
+		mgr.free_page(addr);
+		mgr.free_page(main);
+		let mut ptr = Vec::new();
+		for i in 0..500 {
+			ptr.push(mgr.alloc_memory(64 + i, ZoneId::USER).unwrap());
+		}
+		for addr in ptr {
+			mgr.free_memory(addr);
+		}
+	}
+
+	#[test]
+	fn offline_range() {
+		let arena = Arena::new(1 << 20);
+		let mut mgr = arena.manager(128);
+		let total = mgr.free_page_num(ZoneId::KERNEL) + mgr.free_page_num(ZoneId::USER);
+		let busy = mgr.user_page(2).unwrap() as usize;
+		// 范围内有已分配的页面时报告第一个
+		assert_eq!(mgr.offline_range(busy + PAGE_SIZE, 4 * PAGE_SIZE), Err((busy + PAGE_SIZE) as *mut u8));
+		let free = mgr.free_page_num(ZoneId::USER);
+		let start = busy + 2 * PAGE_SIZE;
+		assert_eq!(mgr.offline_range(start, 4 * PAGE_SIZE - 1), Ok(4));
+		assert_eq!(mgr.free_page_num(ZoneId::USER), free - 4);
+		assert_eq!(mgr.offline_range(start, 2 * PAGE_SIZE), Ok(0));
+		mgr.free_page(busy as *mut u8);
+		let mut page = Vec::new();
+		while let Some(addr) = mgr.user_page(1) {
+			page.push(addr);
+		}
+		assert!(page.iter().all(|addr| !(start..start + 4 * PAGE_SIZE).contains(&(*addr as usize))));
+		for addr in page {
+			mgr.free_page(addr);
+		}
+		assert_eq!(mgr.online_range(busy, 8 * PAGE_SIZE), 4);
+		assert_eq!(mgr.online_range(busy, 8 * PAGE_SIZE), 0);
+		assert_eq!(mgr.free_page_num(ZoneId::KERNEL) + mgr.free_page_num(ZoneId::USER), total);
+	}
+
+	#[test]
+	fn huge_page() {
+		let arena = Arena::new(8 << 20);
+		let mut mgr = arena.manager(256);
+		let free = mgr.free_page_num(ZoneId::USER);
+		let addr = mgr.alloc_huge_page(1, ZoneId::USER).unwrap();
+		assert_eq!(addr as usize % (2 << 20), 0);
+		assert_eq!(mgr.free_page_num(ZoneId::USER), free - 512);
+		let info = mgr.page_info(unsafe {addr.add(511 * PAGE_SIZE)}).unwrap();
+		assert!(info.has(PageBit::Huge));
+		assert_eq!((info.head, info.page_num), (addr, 512));
+		mgr.free_page(addr);
+		assert_eq!(mgr.free_page_num(ZoneId::USER), free);
+		// 无效的 order 及放不下的大页
+		for order in [0, 2, 7, 8, usize::MAX] {
+			assert_eq!(mgr.alloc_huge_page(order, ZoneId::USER), None);
+		}
+		assert_eq!(huge_page_num(1, PAGE_SIZE), Some(512));
+		assert_eq!(huge_page_num(2, PAGE_SIZE), Some(512 * 512));
+		assert_eq!(huge_page_num(7, 1), Some(1 << 63));
+		assert_eq!(huge_page_num(7, 2), None);
+	}
+
+	#[test]
+	fn aligned_pages() {
+		let arena = Arena::new(1 << 20);
+		let mut mgr = arena.manager(128);
+		let free = mgr.free_page_num(ZoneId::KERNEL);
+		// 先占一页，使第一个空闲页面不满足对齐
+		let page = mgr.kernel_page(1).unwrap();
+		for align in [2 * PAGE_SIZE, 16 * PAGE_SIZE, 3 * PAGE_SIZE] {
+			let addr = mgr.alloc_pages_aligned(3, align, ZoneId::KERNEL).unwrap();
+			assert_eq!(addr as usize % align, 0);
+			assert_eq!(mgr.page_info(addr).unwrap().page_num, 3);
+			mgr.free_page(addr);
+		}
+		let addr = mgr.alloc_pages_aligned(2, 16 * PAGE_SIZE, ZoneId::USER).unwrap();
+		assert_eq!(addr as usize % (16 * PAGE_SIZE), 0);
+		mgr.free_page(addr);
+		// 页面数为 0、对齐为 0 或既不是 2 的幂也不是页面的整数倍
+		assert!(mgr.alloc_pages_aligned(0, PAGE_SIZE, ZoneId::KERNEL).is_none());
+		for align in [0, 3, 100, PAGE_SIZE + 8] {
+			assert!(mgr.alloc_pages_aligned(1, align, ZoneId::KERNEL).is_none());
+			assert!(mgr.alloc_dma(1, usize::MAX, align).is_none());
+		}
+		assert!(mgr.alloc_dma(0, usize::MAX, PAGE_SIZE).is_none());
+		assert!(mgr.alloc_page(0, ZoneId::USER).is_none());
+		let addr = mgr.alloc_pages_aligned(1, 64, ZoneId::KERNEL).unwrap();
+		mgr.free_page(addr);
+		mgr.free_page(page);
+		assert_eq!(mgr.free_page_num(ZoneId::KERNEL), free);
+	}
+
+	#[test]
+	fn claim_pages() {
+		let arena = Arena::new(1 << 20);
+		let mut mgr = arena.manager(128);
+		let free = mgr.free_page_num(ZoneId::USER);
+		let addr = arena.start() + 200 * PAGE_SIZE;
+		unsafe {
+			*(addr as *mut u32) = 0xdead_beef;
+		}
+		// 未对齐、已占用（页面数组）或超出段
+		assert!(!mgr.claim_pages((addr + 1) as *mut u8, 2));
+		assert!(!mgr.claim_pages((arena.start() + 32 * PAGE_SIZE) as *mut u8, 1));
+		assert!(!mgr.claim_pages((arena.end() - PAGE_SIZE) as *mut u8, 2));
+		assert!(mgr.claim_pages(addr as *mut u8, 3));
+		// 内容保持不变
+		assert_eq!(unsafe {*(addr as *const u32)}, 0xdead_beef);
+		assert_eq!(mgr.free_page_num(ZoneId::USER), free - 3);
+		assert_eq!(mgr.page_info((addr + 2 * PAGE_SIZE) as *mut u8).unwrap().head, addr as *mut u8);
+		assert!(!mgr.claim_pages((addr + 2 * PAGE_SIZE) as *mut u8, 2));
+		mgr.free_page(addr as *mut u8);
+		assert_eq!(mgr.free_page_num(ZoneId::USER), free);
+	}
+
+	#[test]
+	fn free_after_meta() {
+		let arena = Arena::new(1 << 20);
+		let start = arena.start();
+		let mut page = PageManager::new(start, &[start + 32 * PAGE_SIZE, start + 128 * PAGE_SIZE],
+			&[], arena.end(), PAGE_SIZE);
+		let free = page.free_page_num(ZoneId::KERNEL);
+		let meta_num = PageManager::meta_size(256, 3).div_ceil(PAGE_SIZE);
+		// 紧挨着页面数组的第一次申请
+		let addr = page.alloc_page(1, ZoneId::KERNEL).unwrap();
+		assert_eq!(addr as usize, start + (32 + meta_num) * PAGE_SIZE);
+		assert!(page.put_page(addr));
+		assert_eq!(page.free_page_num(ZoneId::KERNEL), free);
+		let addr = page.alloc_page(3, ZoneId::KERNEL).unwrap();
+		page.free_page(addr);
+		assert_eq!(page.free_page_num(ZoneId::KERNEL), free);
+	}
+
+	#[test]
+	fn refcount() {
+		let arena = Arena::new(1 << 20);
+		let mut mgr = arena.manager(128);
+		let free = mgr.free_page_num(ZoneId::USER);
+		let addr = mgr.user_page(2).unwrap();
+		assert_eq!(mgr.page_count(addr), 1);
+		mgr.get_page(addr);
+		mgr.get_page(addr);
+		assert_eq!(mgr.page_count(addr), 3);
+		assert!(!mgr.put_page(addr));
+		// free_page 同样只减少一次引用
+		mgr.free_page(addr);
+		assert_eq!(mgr.free_page_num(ZoneId::USER), free - 2);
+		assert!(mgr.put_page(addr));
+		assert_eq!(mgr.free_page_num(ZoneId::USER), free);
+	}
+
+	#[test]
+	fn page_info() {
+		let arena = Arena::new(1 << 20);
+		let start = arena.start();
+		let hole = start + 200 * PAGE_SIZE..start + 201 * PAGE_SIZE;
+		let mut page = PageManager::new(start, &[start + 32 * PAGE_SIZE, start + 128 * PAGE_SIZE],
+			core::slice::from_ref(&hole), arena.end(), PAGE_SIZE);
+		let meta_num = PageManager::meta_size(256, 3).div_ceil(PAGE_SIZE);
+		// 页面数组所在的页面与保留页面带保留标志，每页自成一段
+		for i in (0..meta_num).map(|i| start + (32 + i) * PAGE_SIZE).chain([hole.start]) {
+			let info = page.page_info(i as *mut u8).unwrap();
+			assert!(info.has(PageBit::Reserved));
+			assert_eq!((info.head, info.page_num), (i as *mut u8, 1));
+		}
+		assert!(page.page_info(arena.end() as *mut u8).is_none());
+		let addr = page.alloc_page(3, ZoneId::KERNEL).unwrap();
+		page.get_page(addr);
+		let info = page.page_info(unsafe {addr.add(2 * PAGE_SIZE)}).unwrap();
+		assert_eq!(info.head, addr);
+		assert_eq!(info.page_num, 3);
+		assert_eq!(info.count, 2);
+		assert_eq!(info.zone, ZoneId::KERNEL);
+		// 空闲页面自成一段
+		let info = page.page_info(unsafe {addr.add(3 * PAGE_SIZE)}).unwrap();
+		assert_eq!(info.page_num, 1);
+		assert_eq!(info.count, 0);
+	}
+
+	#[test]
+	fn pin_pages() {
+		let arena = Arena::new(1 << 20);
+		let mut mgr = arena.manager(128);
+		let free = mgr.free_page_num(ZoneId::USER);
+		let addr = mgr.user_page_owned(4, 3).unwrap();
+		let page = unsafe {addr.add(PAGE_SIZE)};
+		assert!(mgr.pin_pages(page, 2));
+		assert!(mgr.pin_pages(page, 1));
+		assert!(!mgr.pin_pages(unsafe {addr.add(4 * PAGE_SIZE)}, 1));
+		assert_eq!(mgr.pinned_page_num(ZoneId::USER), 2);
+		// 不能跨过多段页面固定
+		let next = mgr.user_page_owned(2, 3).unwrap();
+		assert_eq!(next, unsafe {addr.add(4 * PAGE_SIZE)});
+		assert!(!mgr.pin_pages(unsafe {addr.add(3 * PAGE_SIZE)}, 2));
+		// 固定期间放弃最后一个引用不会释放，也不能下线
+		assert!(!mgr.put_page(addr));
+		assert_eq!(mgr.page_count(addr), 0);
+		assert_eq!(mgr.offline_range(page as usize, PAGE_SIZE), Err(page));
+		// 每次固定都需要对应的解除，最后一次解除时释放
+		mgr.unpin_pages(page, 2);
+		assert_eq!(mgr.pinned_page_num(ZoneId::USER), 1);
+		assert_eq!(mgr.free_page_num(ZoneId::USER), free - 6);
+		mgr.unpin_pages(page, 1);
+		assert_eq!(mgr.pinned_page_num(ZoneId::USER), 0);
+		assert_eq!(mgr.free_page_num(ZoneId::USER), free - 2);
+		// 进程退出时固定的页面同样在解除固定时释放
+		assert!(mgr.pin_pages(next, 2));
+		assert_eq!(mgr.free_all_owned_by(3), 0);
+		assert_eq!(mgr.free_all_owned_by(3), 0);
+		mgr.unpin_pages(next, 2);
+		assert_eq!(mgr.free_page_num(ZoneId::USER), free);
+	}
+
+	#[test]
+	fn free_runs() {
+		let arena = Arena::new(1 << 20);
+		let start = arena.start();
+		let mut page = PageManager::new(start, &[start + 32 * PAGE_SIZE, start + 128 * PAGE_SIZE],
+			&[], arena.end(), PAGE_SIZE);
+		assert_eq!(page.largest_free_run(ZoneId::USER), 128);
+		assert_eq!(page.fragmentation_index(ZoneId::USER), 0);
+		let mut held = Vec::new();
+		while let Some(addr) = page.alloc_page(1, ZoneId::USER) {
+			held.push(addr);
+		}
+		held.sort();
+		assert_eq!(held.len(), 128);
+		assert_eq!(page.largest_free_run(ZoneId::USER), 0);
+		assert_eq!(page.fragmentation_index(ZoneId::USER), 0);
+		// 隔一页释放一页，总数足够也申请不到两个连续页面
+		for addr in held.iter().skip(1).step_by(2) {
+			page.free_page(*addr);
+		}
+		assert_eq!(page.largest_free_run(ZoneId::USER), 1);
+		assert!(page.alloc_page(2, ZoneId::USER).is_none());
+		let mut histogram = page.free_run_histogram(ZoneId::USER);
+		assert_eq!(histogram[0], 64);
+		assert_eq!(histogram[1..].iter().sum::<usize>(), 0);
+		assert_eq!(page.fragmentation_index(ZoneId::USER), 98);
+		// 前 16 页连成一段
+		for addr in held.iter().take(16).step_by(2) {
+			page.free_page(*addr);
+		}
+		assert_eq!(page.largest_free_run(ZoneId::USER), 16);
+		histogram[0] = 56;
+		histogram[4] = 1;
+		assert_eq!(page.free_run_histogram(ZoneId::USER), histogram);
+		assert_eq!(page.fragmentation_index(ZoneId::USER), (72 - 16) * 100 / 72);
+	}
+
+	#[test]
+	#[should_panic(expected = "overlaps page array")]
+	fn reserved_over_meta() {
+		let arena = Arena::new(1 << 20);
+		let start = arena.start();
+		// 页面数组在内核区域开头
+		let firmware = start + 32 * PAGE_SIZE..start + 33 * PAGE_SIZE;
+		PageManager::new(start, &[start + 32 * PAGE_SIZE, start + 128 * PAGE_SIZE],
+			&[firmware], arena.end(), PAGE_SIZE);
+	}
+}
@@ -0,0 +1,277 @@
+//! # 内存配额
+//! 为每个所有者（进程）设置页面数、堆内存字节数上限，只限制用户区域
+//! 不指明所有者的用户内存（user_page、alloc_memory 等）计入 NO_OWNER，同样可以设置上限
+//! 设有配额的所有者与 NO_OWNER 的使用量在申请、释放时增减，检查时不再统计页面数组与内存池链表
+//!
+//! 2021年4月23日 zg
+
+use crate::{config::QUOTA_NUM, error::MemoryError, require::{NO_OWNER, OwnerId}};
+
+/// ## 配额
+/// page_limit 限制所有者拥有的页面数，包括其堆内存池所在的页面，在申请页面与新建内存池时检查
+/// byte_limit 限制所有者正在使用的堆内存字节数，按对齐后的块大小计算，在申请堆内存时检查
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Quota {
+    pub page_limit : usize,
+    pub byte_limit : usize,
+}
+
+impl Quota {
+    pub const UNLIMITED : Self = Self {
+        page_limit : usize::MAX,
+        byte_limit : usize::MAX,
+    };
+}
+
+/// ## 使用量
+/// 所有者在用户区域拥有的页面数与正在使用的堆内存字节数
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Usage {
+    pub page_num : usize,
+    pub byte_num : usize,
+}
+
+impl Usage {
+    pub const ZERO : Self = Self {
+        page_num : 0,
+        byte_num : 0,
+    };
+}
+
+pub struct QuotaTable {
+    owner : [OwnerId; QUOTA_NUM],
+    quota : [Quota; QUOTA_NUM],
+    usage : [Usage; QUOTA_NUM],
+    /// NO_OWNER 的配额，NO_OWNER 在表中表示空位，单独存放
+    anonymous : Quota,
+    /// NO_OWNER 的使用量，从一开始就计数，清除配额后也不清零
+    anonymous_usage : Usage,
+}
+
+impl QuotaTable {
+    pub const fn new()->Self {
+        Self {
+            owner : [NO_OWNER; QUOTA_NUM],
+            quota : [Quota::UNLIMITED; QUOTA_NUM],
+            usage : [Usage::ZERO; QUOTA_NUM],
+            anonymous : Quota::UNLIMITED,
+            anonymous_usage : Usage::ZERO,
+        }
+    }
+
+    /// ### 是否在为 owner 计数
+    /// 不在表中的所有者设置配额时，需要先统计一次已有的使用量
+    pub fn is_tracked(&self, owner : OwnerId)->bool {
+        owner == NO_OWNER || self.find(owner).is_some()
+    }
+
+    /// ### 检查再使用 page_num 个页面、byte_num 字节是否超出配额
+    /// 不增加的一项不检查，降低配额后已超出的一项不影响另一项
+    pub fn check(&self, owner : OwnerId, page_num : usize, byte_num : usize)->Result<(), MemoryError> {
+        let quota = self.get(owner);
+        let usage = self.usage(owner).unwrap_or(Usage::ZERO);
+        if page_num > 0 && usage.page_num.saturating_add(page_num) > quota.page_limit ||
+                byte_num > 0 && usage.byte_num.saturating_add(byte_num) > quota.byte_limit {
+            return Err(MemoryError::OverQuota);
+        }
+        Ok(())
+    }
+
+    /// ### 记入使用量，未计数的所有者忽略
+    pub fn charge(&mut self, owner : OwnerId, page_num : usize, byte_num : usize) {
+        if let Some(usage) = self.usage_mut(owner) {
+            usage.page_num += page_num;
+            usage.byte_num += byte_num;
+        }
+    }
+
+    /// ### 扣除使用量，未计数的所有者忽略
+    pub fn uncharge(&mut self, owner : OwnerId, page_num : usize, byte_num : usize) {
+        if let Some(usage) = self.usage_mut(owner) {
+            usage.page_num = usage.page_num.saturating_sub(page_num);
+            usage.byte_num = usage.byte_num.saturating_sub(byte_num);
+        }
+    }
+
+    /// ### 以重新统计的结果覆盖使用量
+    pub fn recount(&mut self, owner : OwnerId, usage : Usage) {
+        if let Some(old) = self.usage_mut(owner) {
+            *old = usage;
+        }
+    }
+
+    pub fn usage(&self, owner : OwnerId)->Option<Usage> {
+        if owner == NO_OWNER {
+            return Some(self.anonymous_usage);
+        }
+        self.find(owner).map(|idx| self.usage[idx])
+    }
+
+    pub fn get(&self, owner : OwnerId)->Quota {
+        if owner == NO_OWNER {
+            return self.anonymous;
+        }
+        match self.find(owner) {
+            Some(idx) => self.quota[idx],
+            None => Quota::UNLIMITED,
+        }
+    }
+
+    pub fn set(&mut self, owner : OwnerId, quota : Quota)->Result<(), MemoryError> {
+        if owner == NO_OWNER {
+            self.anonymous = quota;
+            return Ok(());
+        }
+        let idx = match self.find(owner) {
+            Some(idx) => idx,
+            None => {
+                let idx = self.find(NO_OWNER).ok_or(MemoryError::QuotaTableFull)?;
+                self.usage[idx] = Usage::ZERO;
+                idx
+            }
+        };
+        self.owner[idx] = owner;
+        self.quota[idx] = quota;
+        Ok(())
+    }
+
+    pub fn clear(&mut self, owner : OwnerId) {
+        if owner == NO_OWNER {
+            self.anonymous = Quota::UNLIMITED;
+        }
+        else if let Some(idx) = self.find(owner) {
+            self.owner[idx] = NO_OWNER;
+            self.quota[idx] = Quota::UNLIMITED;
+        }
+    }
+
+    fn usage_mut(&mut self, owner : OwnerId)->Option<&mut Usage> {
+        if owner == NO_OWNER {
+            return Some(&mut self.anonymous_usage);
+        }
+        let idx = self.find(owner)?;
+        Some(&mut self.usage[idx])
+    }
+
+    fn find(&self, owner : OwnerId)->Option<usize> {
+        self.owner.iter().position(|o| *o == owner)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{MemoryOp, config::QUOTA_NUM, error::MemoryError, require::NO_OWNER,
+        testing::Arena, zone::ZoneId};
+    use super::Quota;
+
+    #[test]
+    fn owner_quota() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        mgr.set_quota(9, Quota { page_limit : 4, byte_limit : 1000 }).unwrap();
+        let page = mgr.user_page_owned(3, 9).unwrap();
+        assert_eq!(mgr.user_page_owned(2, 9), Err(MemoryError::OverQuota));
+        mgr.user_page_owned(1, 9).unwrap();
+        mgr.free_page(page);
+        // 按实际分到的块大小计算：600 字节占用 1024 字节
+        let a = mgr.alloc_owned(100, 9).unwrap();
+        assert_eq!(mgr.alloc_owned(600, 9), Err(MemoryError::OverQuota));
+        let b = mgr.alloc_owned(300, 9).unwrap();
+        assert_eq!(mgr.alloc_owned(300, 9), Err(MemoryError::OverQuota));
+        // 512 字节的内存池有空位时 200 字节也分到 512 字节的块
+        assert_eq!(mgr.alloc_owned(200, 9), Err(MemoryError::OverQuota));
+        mgr.alloc_owned(100, 9).unwrap();
+        mgr.free_memory(b);
+        mgr.alloc_owned(300, 9).unwrap();
+        mgr.free_memory(a);
+        mgr.clear_quota(9);
+        mgr.alloc_owned(4000, 9).unwrap();
+        mgr.free_all_owned_by(9);
+    }
+
+    #[test]
+    fn anonymous_quota() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        mgr.set_quota(NO_OWNER, Quota { page_limit : 6, byte_limit : 256 }).unwrap();
+        let page = mgr.user_page(4).unwrap();
+        assert!(mgr.user_page(3).is_none());
+        assert!(mgr.alloc_pages_aligned(3, 8192, ZoneId::USER).is_none());
+        assert!(mgr.alloc_page(3, ZoneId::USER).is_none());
+        // 内存池所在的页面同样计入页面配额
+        let a = mgr.alloc_memory(100, ZoneId::USER).unwrap();
+        mgr.alloc_memory(100, ZoneId::USER).unwrap();
+        assert!(mgr.alloc_memory(8, ZoneId::USER).is_none());
+        assert!(mgr.alloc_movable(8, ZoneId::USER).is_none());
+        // 进程的配额、内核区域不受影响
+        mgr.user_page_owned(3, 1).unwrap();
+        mgr.kernel_page(3).unwrap();
+        mgr.alloc_memory(100, ZoneId::KERNEL).unwrap();
+        mgr.free_memory(a);
+        mgr.alloc_memory(100, ZoneId::USER).unwrap();
+        mgr.clear_quota(NO_OWNER);
+        mgr.free_page(page);
+        mgr.user_page(3).unwrap();
+        mgr.alloc_memory(8, ZoneId::USER).unwrap();
+    }
+
+    #[test]
+    fn pool_pages() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        mgr.set_quota(7, Quota { page_limit : 2, byte_limit : usize::MAX }).unwrap();
+        let page = mgr.user_page_owned(2, 7).unwrap();
+        // 新建内存池需要页面，页面配额已满
+        assert_eq!(mgr.alloc_owned(100, 7), Err(MemoryError::OverQuota));
+        mgr.free_page(page);
+        let a = mgr.alloc_owned(100, 7).unwrap();
+        assert_eq!(mgr.user_page_owned(2, 7), Err(MemoryError::OverQuota));
+        // 已有内存池放得下时不再占用页面
+        let b = mgr.alloc_owned(100, 7).unwrap();
+        let page = mgr.user_page_owned(1, 7).unwrap();
+        mgr.free_memory(a);
+        mgr.free_memory(b);
+        mgr.free_page(page);
+        // 空的内存池保留下来，其页面仍然计入
+        assert_eq!(mgr.user_page_owned(2, 7), Err(MemoryError::OverQuota));
+        mgr.free_all_owned_by(7);
+        mgr.user_page_owned(2, 7).unwrap();
+    }
+
+    #[test]
+    fn usage_before_quota() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        let page = mgr.user_page_owned(3, 5).unwrap();
+        mgr.alloc_owned(300, 5).unwrap();
+        // 设置配额时统计已有的使用量
+        // 512 字节的内存池结构体另外存放，共占两个页面
+        mgr.set_quota(5, Quota { page_limit : 6, byte_limit : 1024 }).unwrap();
+        assert_eq!(mgr.user_page_owned(2, 5), Err(MemoryError::OverQuota));
+        assert_eq!(mgr.alloc_owned(600, 5), Err(MemoryError::OverQuota));
+        mgr.user_page_owned(1, 5).unwrap();
+        mgr.alloc_owned(300, 5).unwrap();
+        // 共享的页面释放时转给 NO_OWNER，计入其使用量
+        mgr.get_page(page);
+        mgr.free_all_owned_by(5);
+        mgr.set_quota(NO_OWNER, Quota { page_limit : 4, byte_limit : usize::MAX }).unwrap();
+        assert!(mgr.user_page(2).is_none());
+        mgr.user_page(1).unwrap();
+        mgr.free_page(page);
+        mgr.user_page(3).unwrap();
+    }
+
+    #[test]
+    fn table_full() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        for owner in 0..QUOTA_NUM {
+            mgr.set_quota(owner, Quota::UNLIMITED).unwrap();
+        }
+        assert_eq!(mgr.set_quota(QUOTA_NUM, Quota::UNLIMITED), Err(MemoryError::QuotaTableFull));
+        mgr.set_quota(0, Quota { page_limit : 1, byte_limit : 1 }).unwrap();
+        mgr.set_quota(NO_OWNER, Quota::UNLIMITED).unwrap();
+        mgr.clear_quota(1);
+        mgr.set_quota(QUOTA_NUM, Quota::UNLIMITED).unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+//! # 内存回收
+//! 申请失败时先请求各个 shrinker（如页缓存）释放内存并重试，
+//! 仍然失败再交给 OOM 处理函数选择牺牲者
+//!
+//! 2021年4月25日 zg
+
+use crate::{config::SHRINKER_NUM, error::MemoryError, zone::ZoneId};
+
+/// ## 回收回调
+/// 参数为期望释放的页面数，返回实际释放的页面数
+/// 调用时不持有内存管理器的锁，可以直接释放内存，但不应再申请内存
+pub type Shrinker = fn(page_num : usize)->usize;
+
+/// ## OOM 处理函数
+/// 参数为申请的页面数及申请的区域，返回 true 表示已释放内存（如结束了某个进程），值得重试
+pub type OomHandler = fn(page_num : usize, zone : ZoneId)->bool;
+
+pub struct Reclaim {
+    shrinker : [Option<Shrinker>; SHRINKER_NUM],
+    oom_handler : Option<OomHandler>,
+}
+
+impl Reclaim {
+    pub const fn new()->Self {
+        Self {
+            shrinker : [None; SHRINKER_NUM],
+            oom_handler : None,
+        }
+    }
+
+    /// ### 注册回调，返回的编号用于注销
+    pub fn register(&mut self, shrinker : Shrinker)->Result<usize, MemoryError> {
+        let idx = self.shrinker.iter().position(|s| s.is_none())
+            .ok_or(MemoryError::ShrinkerTableFull)?;
+        self.shrinker[idx] = Some(shrinker);
+        Ok(idx)
+    }
+
+    pub fn unregister(&mut self, idx : usize) {
+        self.shrinker[idx] = None;
+    }
+
+    pub fn set_oom_handler(&mut self, handler : Option<OomHandler>) {
+        self.oom_handler = handler;
+    }
+
+    /// ### 依次调用 shrinker，直到释放够 page_num 页，返回释放的总页面数
+    pub fn shrink(&self, page_num : usize)->usize {
+        let mut cnt = 0;
+        for s in self.shrinker.iter().flatten() {
+            cnt += s(page_num - cnt);
+            if cnt >= page_num {
+                break;
+            }
+        }
+        cnt
+    }
+
+    pub fn oom(&self, page_num : usize, zone : ZoneId)->bool {
+        match self.oom_handler {
+            Some(handler) => handler(page_num, zone),
+            None => false,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use core::{ptr::null_mut, sync::atomic::{AtomicPtr, AtomicUsize, Ordering}};
+    use std::{boxed::Box, sync::Mutex, vec::Vec};
+
+    use crate::{MemoryOp, config::SHRINKER_NUM, error::MemoryError, testing::{Arena, Manager}, zone::ZoneId};
+    use super::Reclaim;
+
+    static CALLED : AtomicUsize = AtomicUsize::new(0);
+
+    fn shrink_some(page_num : usize)->usize {
+        CALLED.fetch_add(1, Ordering::SeqCst);
+        page_num.min(3)
+    }
+
+    #[test]
+    fn shrink_until_enough() {
+        let mut reclaim = Reclaim::new();
+        assert_eq!(reclaim.shrink(4), 0);
+        let a = reclaim.register(shrink_some).unwrap();
+        let b = reclaim.register(shrink_some).unwrap();
+        reclaim.register(shrink_some).unwrap();
+        // 前两个释放够了，不再调用第三个
+        assert_eq!(reclaim.shrink(5), 5);
+        assert_eq!(CALLED.load(Ordering::SeqCst), 2);
+        reclaim.unregister(a);
+        reclaim.unregister(b);
+        assert_eq!(reclaim.shrink(5), 3);
+        assert!(!reclaim.oom(1, ZoneId::USER));
+    }
+
+    #[test]
+    fn table_full() {
+        let mut reclaim = Reclaim::new();
+        for _ in 0..SHRINKER_NUM {
+            reclaim.register(shrink_nothing).unwrap();
+        }
+        assert_eq!(reclaim.register(shrink_nothing), Err(MemoryError::ShrinkerTableFull));
+        reclaim.unregister(1);
+        assert_eq!(reclaim.register(shrink_nothing), Ok(1));
+    }
+
+    fn shrink_nothing(_ : usize)->usize {
+        0
+    }
+
+    /// 记录调用次序：shrinker 记 1，OOM 处理函数记 2
+    static ORDER : Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    fn shrink_record(_ : usize)->usize {
+        ORDER.lock().unwrap().push(1);
+        0
+    }
+
+    fn oom_record(page_num : usize, zone : ZoneId)->bool {
+        assert_eq!((page_num, zone), (1000, ZoneId::USER));
+        ORDER.lock().unwrap().push(2);
+        false
+    }
+
+    #[test]
+    fn oom_after_shrink() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        mgr.register_shrinker(shrink_record).unwrap();
+        mgr.set_oom_handler(Some(oom_record));
+        assert_eq!(mgr.alloc_page(1000, ZoneId::USER), None);
+        assert_eq!(*ORDER.lock().unwrap(), [1, 2]);
+    }
+
+    /// 模拟页缓存：shrinker 通过全局的管理器释放缓存的页面
+    static MANAGER : AtomicPtr<Manager> = AtomicPtr::new(null_mut());
+    static CACHE : Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    fn shrink_cache(page_num : usize)->usize {
+        let mgr = unsafe {&mut *MANAGER.load(Ordering::SeqCst)};
+        let mut cache = CACHE.lock().unwrap();
+        let num = page_num.min(cache.len());
+        for _ in 0..num {
+            mgr.free_page(cache.pop().unwrap() as *mut u8);
+        }
+        num
+    }
+
+    #[test]
+    fn retry_after_shrink() {
+        let arena = Arena::new(1 << 20);
+        let mgr = Box::leak(Box::new(arena.manager(128)));
+        MANAGER.store(mgr, Ordering::SeqCst);
+        let mut page = Vec::new();
+        while let Some(addr) = mgr.user_page(1) {
+            page.push(addr);
+        }
+        while let Some(addr) = mgr.kernel_page(1) {
+            page.push(addr);
+        }
+        CACHE.lock().unwrap().extend(page.drain(..8).map(|p| p as usize));
+        mgr.register_shrinker(shrink_cache).unwrap();
+        assert!(mgr.alloc_page(2, ZoneId::USER).is_some());
+        assert_eq!(mgr.free_page_num(ZoneId::USER), 0);
+        assert_eq!(CACHE.lock().unwrap().len(), 6);
+        // shrinker 释放不出内存时照常失败
+        CACHE.lock().unwrap().clear();
+        assert_eq!(mgr.user_page(1), None);
+    }
+}
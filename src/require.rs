@@ -2,16 +2,113 @@
 //! 
 //! 2021年4月14日 zg
 
+use core::ops::Range;
+
+use crate::{config::FREE_RUN_ORDER, error::MemoryError, heap::HeapPolicy, migrate::Relocate, movable::MovableHandle, page::{PageBit, PageInfo}, quota::Quota,
+    reclaim::{OomHandler, Shrinker}, tag::{Tag, TagUsage},
+    watermark::{LowMemoryHandler, Watermark}, zone::ZoneId};
+#[cfg(feature = "leak-tracker")]
+use crate::leak::LeakTracker;
+
+/// 内存所有者，一般为进程号
+pub type OwnerId = usize;
+/// 不属于任何进程的内存
+pub const NO_OWNER : OwnerId = usize::MAX;
 
 /// ## 页面管理
 /// 页面管理将内存按照 page_size 大小分页，对外提供申请、释放功能
 pub trait PageOp {
     fn clone(&self)->Self;
-    fn new(kmem_start : usize, umem_start : usize,
+    /// 管理 page_num 个页面、zone_num 个区域时，放在内核区域开头的页面数组所占的字节数
+    fn meta_size(page_num : usize, zone_num : usize)->usize;
+    /// mem_start 为 DMA 区域的起始地址，zone_start 为之后各个区域的起始地址，按地址从低到高排列
+    /// 至少包括内核区域，页面数组放在内核区域开头
+    /// reserved 中的物理内存永不分配，与页面数组重叠时在写入页面数组之前 panic
+    fn new(mem_start : usize, zone_start : &[usize], reserved : &[Range<usize>],
         total_mem : usize, page_size : usize)->Self;
-    fn alloc_kernel_page(&mut self, num : usize)->Option<*mut u8>;
-    fn alloc_user_page(&mut self, num : usize)->Option<*mut u8>;
+    fn alloc_page(&mut self, num : usize, zone : ZoneId)->Option<*mut u8>;
+    /// 内核紧急申请，可以使用 min 水位以下的页面
+    fn alloc_kernel_page_critical(&mut self, num : usize)->Option<*mut u8>;
+    /// 从 DMA 区域申请起始地址按 align 对齐、末地址不超过 max_addr 的连续页面
+    /// align 须为 2 的幂或页面大小的整数倍，否则返回 None
+    fn alloc_dma_page(&mut self, num : usize, max_addr : usize, align : usize)->Option<*mut u8>;
+    /// 申请起始地址按 align 字节对齐的连续页面，align 的要求同上
+    fn alloc_pages_aligned(&mut self, num : usize, align : usize, zone : ZoneId)->Option<*mut u8>;
+    /// 占用 addr 开始的 num 个页面，页面必须全部空闲且在同一段内，内容不清零
+    /// 之后可以通过 free_page 正常释放，无法占用时返回 false
+    fn claim_pages(&mut self, addr : *mut u8, num : usize)->bool;
+    /// 申请按自身大小对齐的 order 级大页，Sv39 中 1 级为 2 MiB，2 级为 1 GiB
+    /// order 为 0 或大页超出地址空间时返回 None
+    fn alloc_huge_page(&mut self, order : usize, zone : ZoneId)->Option<*mut u8>;
+    /// 等同于 put_page
     fn free_page(&mut self, addr : *mut u8);
+    /// 增加 addr 开始的一段页面的引用计数，申请时计数为 1
+    fn get_page(&mut self, addr : *mut u8);
+    /// 减少引用计数，减到 0 时释放整段页面并返回 true
+    /// 有固定页面的一段减到 0 时返回 false，等 unpin_pages 解除全部固定后释放
+    fn put_page(&mut self, addr : *mut u8)->bool;
+    fn page_count(&self, addr : *mut u8)->usize;
+    /// 为 addr 开始的一段已分配页面打上标签
+    fn set_tag(&mut self, addr : *mut u8, tag : Tag);
+    /// 统计带有此标签的已分配页面数量
+    fn tag_page_num(&self, tag : Tag)->usize;
+    /// 记录 addr 开始的一段已分配页面属于哪个进程
+    fn set_owner(&mut self, addr : *mut u8, owner : OwnerId);
+    /// 固定 addr 开始的 num 个已分配页面，可以重复固定，固定期间不能释放
+    /// 有页面未分配或范围跨过多段页面时返回 false
+    fn pin_pages(&mut self, addr : *mut u8, num : usize)->bool;
+    /// 固定期间已放弃全部引用的一段页面，解除全部固定后释放并返回 true
+    fn unpin_pages(&mut self, addr : *mut u8, num : usize)->bool;
+    fn pinned_page_num(&self, zone : ZoneId)->usize;
+    /// addr 开始的一段已分配页面只有一个引用且没有固定的页面，可以搬动或归还
+    fn can_move(&self, addr : *mut u8)->bool;
+    /// 区域中最长的连续空闲页面数
+    fn largest_free_run(&self, zone : ZoneId)->usize;
+    /// 按长度统计连续空闲页面的段数，第 i 档为长度在 [2^i, 2^(i+1)) 的段
+    fn free_run_histogram(&self, zone : ZoneId)->[usize; FREE_RUN_ORDER];
+    /// 碎片程度，0 到 100，为不在最长空闲段中的空闲页面所占的百分比
+    fn fragmentation_index(&self, zone : ZoneId)->usize;
+    /// 找到 num 个连续页面，其中只有空闲页面和 is_movable 认可的一段段页面
+    /// is_movable 的参数为一段页面第一页的地址，固定或被共享的页面不能移动
+    /// 窗口起始地址按 align 对齐，最后一个字节不超过 max_addr
+    fn movable_window(&self, num : usize, align : usize, max_addr : usize, zone : ZoneId,
+        is_movable : impl Fn(*mut u8)->bool)->Option<*mut u8>;
+    /// 为 addr 开始的一段已分配页面设置、清除用途标志
+    fn set_flag(&mut self, addr : *mut u8, bit : PageBit);
+    fn clear_flag(&mut self, addr : *mut u8, bit : PageBit);
+    /// 查询页面的状态与用途，不在管理范围内时返回 None
+    /// 保留页面（包括页面数组所在的页面）带 Reserved 标志，由调用者自行过滤
+    fn page_info(&self, addr : *mut u8)->Option<PageInfo>;
+    /// 一次遍历页面，此进程拥有的每段页面放弃一次引用，同 put_page，返回释放的页面数
+    /// 仍被共享的页面不释放，改为不属于任何进程；固定的页面解除固定时才释放
+    fn free_all_owned_by(&mut self, owner : OwnerId)->usize;
+    /// 统计此进程在区域中拥有的页面数量，NO_OWNER 统计不属于任何进程的已分配页面
+    fn owned_page_num(&self, owner : OwnerId, zone : ZoneId)->usize;
+    fn free_page_num(&self, zone : ZoneId)->usize;
+    /// 设置区域的水位，普通申请不能使空闲页面低于 min
+    /// 不满足 min <= low <= high 时返回 InvalidWatermark，不修改原有的水位
+    fn set_watermark(&mut self, zone : ZoneId, mark : Watermark)->Result<(), MemoryError>;
+    /// 空闲页面是否低于 low 水位，回到 high 以上后恢复
+    fn is_low_memory(&self, zone : ZoneId)->bool;
+    /// 空闲页面跌破 low 水位时调用
+    fn set_low_memory_handler(&mut self, handler : Option<LowMemoryHandler>);
+    /// 将一段物理内存标记为保留，永不分配、释放，两端按页面向外取整
+    /// 其中有已分配的页面时不做修改并返回 false
+    fn reserve_range(&mut self, range : Range<usize>)->bool;
+    /// 将范围内的空闲页面下线，两端按页面向外取整，保留的页面不受影响
+    /// 有已分配的页面时不做修改，返回第一个这样的页面，否则返回下线的页面数
+    fn offline_range(&mut self, start : usize, len : usize)->Result<usize, *mut u8>;
+    /// 将范围内下线的页面恢复为空闲，返回恢复的页面数
+    fn online_range(&mut self, start : usize, len : usize)->usize;
+    /// 加入一段新的物理内存，其开头存放该段的页面数组
+    /// 与已管理的内存重叠、过小或区域不存在时返回错误
+    fn add_region(&mut self, start : usize, len : usize, zone : ZoneId)->Result<(), MemoryError>;
+    fn zone_num(&self)->usize;
+    /// 地址所在的区域，不在任何区域内时返回 None
+    fn zone_of(&self, addr : *mut u8)->Option<ZoneId>;
+    /// 移动此区域与相邻区域的边界，从相邻区域取得空闲页面，
+    /// 使此区域在边界处有 num 个连续的空闲页面，无法满足时返回 false
+    fn expand_zone(&mut self, zone : ZoneId, num : usize)->bool;
     fn page_size(&self)->usize;
     fn print(&self);
 }
@@ -20,15 +117,48 @@ pub trait PageOp {
 /// 基于页面管理提供任意大小的内存分配功能
 pub trait HeapOp<T:PageOp> {
     fn new(page : T)->Self;
-    fn alloc_kernel_memory(&mut self, size : usize)->Option<*mut u8>;
-    fn alloc_user_memory(&mut self, size : usize)->Option<*mut u8>;
-    fn free_kernel_memory(&mut self, addr : *mut u8);
-    fn free_user_memory(&mut self, addr : *mut u8);
+    fn alloc_memory(&mut self, size : usize, zone : ZoneId)->Option<*mut u8>;
+    /// 申请带标签的内核内存，同一标签的内存放在相同的内存池中
+    fn alloc_tagged_memory(&mut self, size : usize, tag : Tag)->Option<*mut u8>;
+    /// 统计带有此标签、正在使用的堆内存字节数
+    fn tag_memory_size(&self, tag : Tag)->usize;
+    /// 申请属于某个进程的用户内存，不同进程的内存不共用内存池
+    fn alloc_owned_memory(&mut self, size : usize, owner : OwnerId)->Option<*mut u8>;
+    /// 将此进程的内存池从各区域的内存池链表中移除
+    /// 内存池所在的页面由 PageOp::free_all_owned_by 统一回收
+    fn remove_owned_pools(&mut self, owner : OwnerId);
+    /// 统计此进程在区域中正在使用的堆内存字节数
+    fn owned_memory_size(&self, owner : OwnerId, zone : ZoneId)->usize;
+    /// 此进程在区域中申请 size 字节时实际占用的块大小
+    fn block_size(&self, size : usize, zone : ZoneId, owner : OwnerId)->usize;
+    /// 在区域中申请 size 字节的可移动内存时实际占用的字节数，包括句柄槽位
+    fn movable_size(&self, size : usize, zone : ZoneId)->usize;
+    /// 此进程在区域中申请 size 字节时最多需要新建内存池的页面数，已有内存池放得下时为 0
+    fn pool_page_need(&self, size : usize, zone : ZoneId, owner : OwnerId)->usize;
+    /// 在区域中申请 size 字节的可移动内存时最多需要新建内存池的页面数
+    fn movable_page_need(&self, size : usize, zone : ZoneId)->usize;
+    /// addr 所在块的所属进程与块大小，addr 须是区域中已申请的堆内存
+    fn block_of(&self, addr : *mut u8, zone : ZoneId)->(OwnerId, usize);
+    /// zone 为 addr 所在的区域
+    fn free_memory(&mut self, addr : *mut u8, zone : ZoneId);
+    /// 申请可移动内存，块放在专用的内存池中，不记录到泄漏追踪
+    fn alloc_movable(&mut self, size : usize, zone : ZoneId)->Option<MovableHandle>;
+    fn free_movable(&mut self, handle : MovableHandle, zone : ZoneId);
+    /// 搬空稀疏的可移动内存池，返回归还的页面数
+    fn compact(&mut self, zone : ZoneId)->usize;
+    /// 泄漏追踪记录，仅记录通过上面接口申请的内存
+    #[cfg(feature = "leak-tracker")]
+    fn tracker(&self)->&LeakTracker;
+    /// 新建内存池以容纳 size 大小的块时需要的页面数
+    fn pool_page_num(&self, size : usize)->usize;
+    /// 须在申请任何堆内存之前设置
+    fn set_policy(&mut self, policy : HeapPolicy);
     fn print(&self);
 }
 
 /// ## 内存管理接口
 /// 统御堆内存、页面管理，作为对外提供功能的接口
+/// 区域不存在时申请返回 None 或 InvalidZone，统计返回 0
 pub trait MemoryOp {
     fn free_memory(&mut self, addr : *mut u8);
 
@@ -36,13 +166,198 @@ pub trait MemoryOp {
 
     fn user_page(&mut self, num : usize)->Option<*mut u8>;
 
+    fn alloc_page(&mut self, num : usize, zone : ZoneId)->Option<*mut u8>;
+
+    /// ## 内核紧急页面
+    /// 可以使用 min 水位以下的保留页面，不会调用回收回调
+    fn kernel_page_critical(&mut self, num : usize)->Option<*mut u8>;
+
+    /// ## 释放页面
+    /// 页面被共享时只减少引用计数，最后一个引用释放后才归还
     fn free_page(&mut self, addr : *mut u8);
 
-    fn alloc_memory(&mut self, size : usize, is_kernel : bool)->Option<*mut u8>;
+    /// ## 共享页面
+    /// 增加一段已分配页面的引用计数，如 fork 时父子进程共享的写时复制页面
+    /// addr 必须是申请时返回的地址
+    fn get_page(&mut self, addr : *mut u8);
+
+    /// ## 释放一个引用
+    /// 与 free_page 相同，返回页面是否真正被释放
+    /// 固定的页面放弃最后一个引用时返回 false，解除全部固定时自动释放，不需要再次调用
+    fn put_page(&mut self, addr : *mut u8)->bool;
+
+    /// ## 引用计数
+    /// 写时复制时计数为 1 的页面可以直接改为可写
+    fn page_count(&mut self, addr : *mut u8)->usize;
+
+    /// ## 对齐的连续页面
+    /// 起始地址按 align 字节对齐，供页表根、DMA 环形缓冲区等使用
+    /// num 为 0，或 align 既不是 2 的幂也不是页面大小的整数倍时返回 None
+    fn alloc_pages_aligned(&mut self, num : usize, align : usize, zone : ZoneId)->Option<*mut u8>;
+
+    /// ## 占用指定的物理页面
+    /// 用于固件、内核交接等必须使用已知地址的场合，页面内容保持不变
+    /// 范围内有页面已被占用或不在同一区域时返回 false
+    fn claim_pages(&mut self, addr : *mut u8, num : usize)->bool;
+
+    /// ## 大页
+    /// 物理连续且按自身大小对齐，供页表建立大页映射，通过 free_page 整体释放
+    /// order 为 1 时包含 512 个页面，即 Sv39 的 2 MiB 大页；为 2 时为 1 GiB 大页
+    /// order 为 0 或大页超出地址空间时返回 None
+    fn alloc_huge_page(&mut self, order : usize, zone : ZoneId)->Option<*mut u8>;
+
+    /// ## DMA 页面
+    /// 从 DMA 区域申请物理连续的页面，起始地址按 align 对齐，最后一个字节不超过 max_addr
+    /// 参数的要求同 alloc_pages_aligned
+    /// 通过 free_page 释放，也可以使用 DmaBuffer 自动释放
+    fn alloc_dma(&mut self, num : usize, max_addr : usize, align : usize)->Option<*mut u8>;
+
+    fn alloc_memory(&mut self, size : usize, zone : ZoneId)->Option<*mut u8>;
+
+    /// ## 可移动内存
+    /// 通过句柄访问，compact 时可能被搬到其它位置，每次使用前通过 addr 取得地址
+    fn alloc_movable(&mut self, size : usize, zone : ZoneId)->Option<MovableHandle>;
+
+    fn free_movable(&mut self, handle : MovableHandle);
+
+    /// ## 整理堆内存
+    /// 将可移动内存从稀疏的内存池搬到同类更满的内存池，归还搬空的内存池
+    /// 返回归还的页面数
+    fn compact(&mut self)->usize;
+
+    /// ## 紧急内核内存
+    /// 供中断处理等不能失败、不能等待的调用者使用，不会调用回收回调
+    /// 堆内存不足时从保留页面中取出一整页（size 不超过页面大小），通过 free_memory 归还
+    fn alloc_memory_atomic(&mut self, size : usize)->Option<*mut u8>;
+
+    /// ## 可用的保留页面数
+    fn reserve_page_num(&mut self)->usize;
+
+    /// ## 带标签的内核内存
+    /// 标签记录内存所属的子系统，可以通过 tag_usage 查询
+    fn alloc_tagged(&mut self, size : usize, tag : Tag)->Option<*mut u8>;
+
+    fn kernel_page_tagged(&mut self, num : usize, tag : Tag)->Option<*mut u8>;
+
+    /// ## 按标签统计
+    /// 返回此标签占用的页面数以及堆内存字节数
+    fn tag_usage(&mut self, tag : Tag)->TagUsage;
+
+    /// ## 属于某个进程的用户页面
+    /// 超出配额时返回 OverQuota
+    /// 在用户区域申请页面、堆内存的其它接口计入 NO_OWNER 的配额，超出时返回 None
+    fn user_page_owned(&mut self, num : usize, owner : OwnerId)->Result<*mut u8, MemoryError>;
+
+    /// ## 属于某个进程的用户内存
+    /// 进程退出时通过 free_all_owned_by 一并释放，超出配额时返回 OverQuota
+    fn alloc_owned(&mut self, size : usize, owner : OwnerId)->Result<*mut u8, MemoryError>;
+
+    /// ## 释放进程的全部用户内存
+    /// 包括页面与堆内存，返回释放的页面数
+    /// 每段页面放弃一次引用，仍被其它进程共享的页面保留，之后不再计入此进程
+    /// 固定的页面在解除固定时释放，之前仍计入此进程
+    /// 配额不会被清除，需要时调用 clear_quota
+    fn free_all_owned_by(&mut self, owner : OwnerId)->usize;
+
+    /// ## 设置进程配额
+    /// owner 为 NO_OWNER 时限制不属于任何进程的用户内存
+    /// 配额表已满时返回 QuotaTableFull
+    fn set_quota(&mut self, owner : OwnerId, quota : Quota)->Result<(), MemoryError>;
+
+    fn clear_quota(&mut self, owner : OwnerId);
+
+    /// ## 注册回收回调
+    /// 申请失败时依次调用，返回的编号用于注销
+    fn register_shrinker(&mut self, shrinker : Shrinker)->Result<usize, MemoryError>;
+
+    fn unregister_shrinker(&mut self, idx : usize);
+
+    /// ## 设置 OOM 处理函数
+    /// 回收回调无法释放足够内存时调用，之后再重试一次
+    fn set_oom_handler(&mut self, handler : Option<OomHandler>);
+
+    fn free_page_num(&mut self, zone : ZoneId)->usize;
+
+    /// ## 设置水位
+    /// 单位为页面，需满足 min <= low <= high，否则返回 InvalidWatermark
+    fn set_watermark(&mut self, zone : ZoneId, mark : Watermark)->Result<(), MemoryError>;
+
+    /// ## 是否内存紧张
+    /// 空闲页面低于 low 水位时为真，回到 high 水位以上后恢复，供调度器轮询
+    fn is_low_memory(&mut self, zone : ZoneId)->bool;
+
+    /// ## 内存紧张通知
+    /// 回调在持有锁时调用，不能申请、释放内存
+    fn set_low_memory_handler(&mut self, handler : Option<LowMemoryHandler>);
+
+    /// ## 保留物理内存
+    /// 如固件、设备树、MMIO 所在的范围，其中的页面永不分配
+    /// 范围内有已分配的页面时返回 false
+    fn reserve_range(&mut self, range : Range<usize>)->bool;
+
+    /// ## 内存下线
+    /// 范围内有已分配的页面时返回其中第一个页面的地址
+    fn offline_range(&mut self, start : usize, len : usize)->Result<usize, *mut u8>;
+
+    /// ## 内存上线
+    /// 恢复 offline_range 下线的页面，返回页面数
+    fn online_range(&mut self, start : usize, len : usize)->usize;
+
+    /// ## 固定页面
+    /// 设备正在读写的页面在 unpin_pages 之前不能释放、下线或移动
+    /// 可以固定一段页面中的一部分，每次固定都需要对应的解除
+    /// 固定期间放弃了全部引用的页面在最后一次解除时释放
+    fn pin_pages(&mut self, addr : *mut u8, num : usize)->bool;
+
+    fn unpin_pages(&mut self, addr : *mut u8, num : usize);
+
+    /// ## 固定的页面数
+    fn pinned_page_num(&mut self, zone : ZoneId)->usize;
+
+    /// ## 最长的连续空闲页面数
+    /// 申请更多连续页面时一定失败，即使空闲页面总数足够
+    fn largest_free_run(&mut self, zone : ZoneId)->usize;
+
+    /// ## 空闲段长度分布
+    /// 第 i 档为长度在 [2^i, 2^(i+1)) 的连续空闲页面段数，最后一档不设上限
+    fn free_run_histogram(&mut self, zone : ZoneId)->[usize; FREE_RUN_ORDER];
+
+    /// ## 碎片指数
+    /// 0 表示空闲页面全部连续，越接近 100 越零散，可以据此决定是否整理
+    fn fragmentation_index(&mut self, zone : ZoneId)->usize;
+
+    /// ## 登记可移动的页面
+    /// addr 为申请时返回的地址，整段页面可以被搬到别处，搬动后调用 relocate 更新引用
+    /// 通过 free_page 释放后自动注销，其它途径释放的页面在迁移前检查时注销
+    /// addr 不是一段已分配页面的起始地址时返回 InvalidPage
+    fn register_movable(&mut self, addr : *mut u8, relocate : Relocate)->Result<(), MemoryError>;
+
+    fn unregister_movable(&mut self, addr : *mut u8);
+
+    /// ## 迁移页面
+    /// 搬走可移动的页面，在区域中腾出 num 个连续的空闲页面，窗口全部空闲时返回 true
+    /// 标签、所属进程与用途标志随页面一起搬走，固定或被共享的页面不移动
+    /// 连续页面申请因碎片失败时会自动尝试，重定位回调在解锁后调用
+    fn migrate_pages(&mut self, num : usize, zone : ZoneId)->bool;
+
+    /// ## 页面用途标志
+    /// 标记一段已分配页面的用途，如页表、对象缓存，只能设置用途类的标志
+    fn set_page_flag(&mut self, addr : *mut u8, bit : PageBit);
+
+    fn clear_page_flag(&mut self, addr : *mut u8, bit : PageBit);
+
+    /// ## 页面信息
+    /// 查询物理页面属于哪个区域、进程，用于什么，供调试使用
+    /// 保留页面也返回信息，带 Reserved 标志
+    fn page_info(&mut self, addr : *mut u8)->Option<PageInfo>;
+
+    /// ## 热插入内存
+    /// 将启动后发现的内存加入区域，不要求与已有内存相邻
+    fn add_region(&mut self, start : usize, len : usize, zone : ZoneId)->Result<(), MemoryError>;
 
     fn print(&mut self);
 }
-#[allow(clippy::drop_bounds)]
+#[allow(drop_bounds)]
 pub trait AutoMemory<T1:Copy> {
     fn new(size : usize)->Self;
 
@@ -0,0 +1,155 @@
+//! # 紧急保留页面
+//! 创建内存管理器时预留若干内核页面，只交给紧急申请（中断处理、块设备请求等）
+//! 堆内存无法满足紧急申请时从这里取出整页，释放时放回
+//!
+//! 2021年4月28日 zg
+
+use core::ptr::null_mut;
+
+use crate::{config::{RESERVE_PAGE_NUM, RESERVE_TAKEN_NUM}, require::PageOp};
+
+pub struct Reserve {
+    /// 可以交出的页面，空位为 null
+    page : [*mut u8; RESERVE_PAGE_NUM],
+    /// 已交给调用者、尚未放回的页面
+    taken : [*mut u8; RESERVE_TAKEN_NUM],
+}
+
+impl Reserve {
+    pub const fn new()->Self {
+        Self {
+            page : [null_mut(); RESERVE_PAGE_NUM],
+            taken : [null_mut(); RESERVE_TAKEN_NUM],
+        }
+    }
+
+    /// ### 补齐保留页面
+    /// 创建时以及之后有内存释放时调用，空位使用紧急申请填充
+    pub fn refill<T:PageOp>(&mut self, page : &mut T) {
+        for slot in self.page.iter_mut().filter(|p| p.is_null()) {
+            match page.alloc_kernel_page_critical(1) {
+                Some(addr) => *slot = addr,
+                None => break,
+            }
+        }
+    }
+
+    pub fn is_full(&self)->bool {
+        self.page.iter().all(|p| !p.is_null())
+    }
+
+    /// ### 取出一个保留页面
+    /// 取出后空出的位置由之后的 refill 补上
+    pub fn take(&mut self, page_size : usize)->Option<*mut u8> {
+        let taken = self.taken.iter().position(|p| p.is_null())?;
+        let slot = self.page.iter_mut().find(|p| !p.is_null())?;
+        let addr = *slot;
+        *slot = null_mut();
+        self.taken[taken] = addr;
+        unsafe {
+            addr.write_bytes(0, page_size);
+        }
+        Some(addr)
+    }
+
+    /// ### 放回保留页面
+    /// 有空位时留作保留页面，否则归还给页面管理器
+    /// 地址不是取出的保留页面时返回 false
+    pub fn give_back<T:PageOp>(&mut self, addr : *mut u8, page : &mut T)->bool {
+        let taken = match self.taken.iter_mut().find(|p| **p == addr) {
+            Some(taken) => taken,
+            None => return false,
+        };
+        *taken = null_mut();
+        match self.page.iter_mut().find(|p| p.is_null()) {
+            Some(slot) => *slot = addr,
+            None => page.free_page(addr),
+        }
+        true
+    }
+
+    /// ### 可用的保留页面数
+    pub fn available(&self)->usize {
+        let room = self.taken.iter().filter(|p| p.is_null()).count();
+        self.page.iter().filter(|p| !p.is_null()).count().min(room)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::vec::Vec;
+
+    use crate::{MemoryOp, config::{PAGE_SIZE, RESERVE_PAGE_NUM}, testing::Arena,
+        watermark::Watermark, zone::ZoneId};
+
+    #[test]
+    fn atomic_from_reserve() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        assert_eq!(mgr.reserve_page_num(), RESERVE_PAGE_NUM);
+        // 用户区域不借出页面，耗尽内核区域
+        let user_free = mgr.free_page_num(ZoneId::USER);
+        mgr.set_watermark(ZoneId::USER, Watermark::new(user_free, user_free, user_free)).unwrap();
+        let mut page = Vec::new();
+        while let Some(addr) = mgr.kernel_page(1) {
+            page.push(addr);
+        }
+        assert_eq!(mgr.alloc_memory(PAGE_SIZE, ZoneId::KERNEL), None);
+        let mut atomic = Vec::new();
+        for _ in 0..RESERVE_PAGE_NUM {
+            let addr = mgr.alloc_memory_atomic(PAGE_SIZE).unwrap();
+            assert!(!atomic.contains(&addr));
+            atomic.push(addr);
+        }
+        assert_eq!(mgr.reserve_page_num(), 0);
+        assert_eq!(mgr.alloc_memory_atomic(8), None);
+        // 放回后可以再次取出，取出时清零
+        unsafe {
+            atomic[0].write_bytes(7, PAGE_SIZE);
+        }
+        mgr.free_memory(atomic[0]);
+        assert_eq!(mgr.reserve_page_num(), 1);
+        let addr = mgr.alloc_memory_atomic(PAGE_SIZE).unwrap();
+        assert_eq!(addr, atomic[0]);
+        assert_eq!(unsafe {*addr.add(PAGE_SIZE - 1)}, 0);
+        for addr in atomic {
+            mgr.free_memory(addr);
+        }
+        assert_eq!(mgr.reserve_page_num(), RESERVE_PAGE_NUM);
+        for addr in page {
+            mgr.free_page(addr);
+        }
+        // 内存充足时不动用保留页面
+        let addr = mgr.alloc_memory_atomic(64).unwrap();
+        assert_eq!(mgr.reserve_page_num(), RESERVE_PAGE_NUM);
+        mgr.free_memory(addr);
+    }
+
+    #[test]
+    fn refill_after_drain() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        let kernel_free = mgr.free_page_num(ZoneId::KERNEL);
+        let user_free = mgr.free_page_num(ZoneId::USER);
+        mgr.set_watermark(ZoneId::USER, Watermark::new(user_free, user_free, user_free)).unwrap();
+        let mut page = Vec::new();
+        while let Some(addr) = mgr.kernel_page(1) {
+            page.push(addr);
+        }
+        let atomic : Vec<_> = (0..RESERVE_PAGE_NUM)
+            .map(|_| mgr.alloc_memory_atomic(PAGE_SIZE).unwrap()).collect();
+        assert_eq!(mgr.reserve_page_num(), 0);
+        // 其它内核内存释放后补齐，交出的页面仍可使用
+        for addr in page {
+            mgr.free_page(addr);
+        }
+        assert_eq!(mgr.reserve_page_num(), RESERVE_PAGE_NUM);
+        // 保留页面已满时，放回的页面归还给页面管理器
+        for addr in atomic {
+            mgr.free_memory(addr);
+        }
+        assert_eq!(mgr.reserve_page_num(), RESERVE_PAGE_NUM);
+        assert_eq!(mgr.free_page_num(ZoneId::KERNEL), kernel_free);
+    }
+}
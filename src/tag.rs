@@ -0,0 +1,64 @@
+//! # 内存标签
+//! 记录内存属于哪个子系统，便于按使用者统计内存、排查泄漏
+//!
+//! 2021年4月20日 zg
+
+/// ## 内存标签
+/// 小整数编号，0 表示未标记
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Tag(pub u8);
+
+impl Tag {
+    pub const NONE : Self = Self(0);
+    pub const FS : Self = Self(1);
+    pub const NET : Self = Self(2);
+    pub const SCHED : Self = Self(3);
+    pub const DRIVER : Self = Self(4);
+
+    pub const fn val(self)->u8 {
+        self.0
+    }
+}
+
+/// ## 标签使用情况
+#[derive(Copy, Clone, Default, Debug)]
+pub struct TagUsage {
+    /// 占用的页面数，包括堆内存池所在的页面
+    pub page_num : usize,
+    /// 堆内存中正在使用的字节数（按对齐后的块大小计算）
+    pub heap_size : usize,
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{MemoryOp, testing::Arena, zone::ZoneId};
+    use super::Tag;
+
+    #[test]
+    fn tag_usage() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        let a = mgr.alloc_tagged(100, Tag::FS).unwrap();
+        let b = mgr.alloc_tagged(100, Tag::FS).unwrap();
+        let c = mgr.alloc_tagged(30, Tag::NET).unwrap();
+        let page = mgr.kernel_page_tagged(3, Tag::NET).unwrap();
+        let untagged = mgr.alloc_memory(100, ZoneId::KERNEL).unwrap();
+        // 按对齐后的块大小统计，不同标签不共用内存池
+        assert_eq!(mgr.tag_usage(Tag::FS).heap_size, 256);
+        let net = mgr.tag_usage(Tag::NET);
+        assert_eq!(net.heap_size, 32);
+        assert!(net.page_num >= 4);
+        assert_eq!(mgr.tag_usage(Tag::SCHED).heap_size, 0);
+        assert_eq!(mgr.tag_usage(Tag::SCHED).page_num, 0);
+
+        mgr.free_memory(a);
+        assert_eq!(mgr.tag_usage(Tag::FS).heap_size, 128);
+        mgr.free_memory(b);
+        mgr.free_memory(c);
+        mgr.free_page(page);
+        mgr.free_memory(untagged);
+        assert_eq!(mgr.tag_usage(Tag::FS).heap_size, 0);
+        assert_eq!(mgr.tag_usage(Tag::NET).heap_size, 0);
+        assert!(mgr.tag_usage(Tag::NET).page_num < net.page_num);
+    }
+}
@@ -0,0 +1,53 @@
+//! # 测试辅助
+//! 测试在普通进程中运行，用按页面对齐的堆内存模拟物理内存
+//!
+//! 2021年5月8日 zg
+
+use std::alloc::{Layout, alloc_zeroed, dealloc};
+
+use crate::{config::PAGE_SIZE, heap::Heap, manager::MemoryManager, page::PageManager,
+    zone::default_dma_page_num};
+
+pub type Manager = MemoryManager<PageManager, Heap<PageManager>>;
+
+/// ## 模拟的物理内存
+/// 需比使用它的管理器存活更久，先于管理器声明即可
+pub struct Arena {
+    start : usize,
+    layout : Layout,
+}
+
+impl Arena {
+    pub fn new(size : usize)->Self {
+        Self::aligned(size, PAGE_SIZE)
+    }
+
+    pub fn aligned(size : usize, align : usize)->Self {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let start = unsafe {alloc_zeroed(layout)} as usize;
+        assert!(start != 0, "test arena allocation failed");
+        Self { start, layout }
+    }
+
+    pub fn start(&self)->usize {
+        self.start
+    }
+
+    pub fn end(&self)->usize {
+        self.start + self.layout.size()
+    }
+
+    /// ### 最低处为默认大小的 DMA 区域，之后内核区域为 kernel_page_num 页，其余归用户区域
+    pub fn manager(&self, kernel_page_num : usize)->Manager {
+        let dma_page_num = default_dma_page_num(self.layout.size() / PAGE_SIZE);
+        Manager::with_zones(self.start, &[dma_page_num, kernel_page_num], &[], PAGE_SIZE, self.end())
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        unsafe {
+            dealloc(self.start as *mut u8, self.layout);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! # 水位
+//! 每个区域的空闲页面设有 min、low、high 三条水位线
+//! 空闲页面低于 low 时标记为内存紧张并通知，回到 high 以上后恢复
+//! min 以下的页面只留给内核紧急申请
+//!
+//! 2021年4月27日 zg
+
+use crate::zone::ZoneId;
+
+/// ## 水位线
+/// 单位为页面数，需满足 min <= low <= high
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Watermark {
+    pub min : usize,
+    pub low : usize,
+    pub high : usize,
+}
+
+impl Watermark {
+    pub const fn new(min : usize, low : usize, high : usize)->Self {
+        Self { min, low, high }
+    }
+
+    pub fn is_valid(&self)->bool {
+        self.min <= self.low && self.low <= self.high
+    }
+}
+
+/// ## 内存紧张通知
+/// 参数为内存紧张的区域
+/// 调用时持有页面管理的锁，回调中不能申请、释放内存，一般只设置标记
+pub type LowMemoryHandler = fn(zone : ZoneId);
+
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::vec::Vec;
+
+    use crate::{MemoryOp, error::MemoryError, testing::Arena, zone::ZoneId};
+    use super::Watermark;
+
+    static NOTIFIED : AtomicUsize = AtomicUsize::new(0);
+
+    fn notify(zone : ZoneId) {
+        assert_eq!(zone, ZoneId::KERNEL);
+        NOTIFIED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn low_memory() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        // 用户区域不借出页面，内核区域只能使用自己的页面
+        let user_free = mgr.free_page_num(ZoneId::USER);
+        mgr.set_watermark(ZoneId::USER, Watermark::new(user_free, user_free, user_free)).unwrap();
+        let free = mgr.free_page_num(ZoneId::KERNEL);
+        mgr.set_watermark(ZoneId::KERNEL, Watermark::new(4, 8, 16)).unwrap();
+        mgr.set_low_memory_handler(Some(notify));
+        let mut page = Vec::new();
+        while let Some(addr) = mgr.kernel_page(1) {
+            page.push(addr);
+        }
+        // min 以下的页面只留给紧急申请
+        assert_eq!(page.len(), free - 4);
+        assert_eq!(NOTIFIED.load(Ordering::SeqCst), 1);
+        assert!(mgr.is_low_memory(ZoneId::KERNEL));
+        assert_eq!(mgr.user_page(1), None);
+        page.push(mgr.kernel_page_critical(1).unwrap());
+        assert_eq!(mgr.free_page_num(ZoneId::KERNEL), 3);
+        // 回到 high 以上才恢复
+        for _ in 0..12 {
+            mgr.free_page(page.pop().unwrap());
+        }
+        assert!(mgr.is_low_memory(ZoneId::KERNEL));
+        mgr.free_page(page.pop().unwrap());
+        assert!(!mgr.is_low_memory(ZoneId::KERNEL));
+        assert!(!mgr.is_low_memory(ZoneId::USER));
+        // 再次跌破 low 时重新通知
+        for _ in 0..9 {
+            page.push(mgr.kernel_page(1).unwrap());
+        }
+        assert_eq!(NOTIFIED.load(Ordering::SeqCst), 2);
+        for addr in page {
+            mgr.free_page(addr);
+        }
+        assert_eq!(mgr.free_page_num(ZoneId::KERNEL), free);
+    }
+
+    #[test]
+    fn valid() {
+        assert!(Watermark::new(1, 1, 2).is_valid());
+        assert!(!Watermark::new(2, 1, 3).is_valid());
+        assert!(!Watermark::new(1, 3, 2).is_valid());
+    }
+
+    #[test]
+    fn reject_invalid() {
+        let arena = Arena::new(1 << 20);
+        let mut mgr = arena.manager(128);
+        let free = mgr.free_page_num(ZoneId::KERNEL);
+        mgr.set_watermark(ZoneId::KERNEL, Watermark::new(4, 8, 16)).unwrap();
+        // 无效的水位不生效，原有的水位保持不变
+        for mark in [Watermark::new(2, 1, 3), Watermark::new(1, 3, 2), Watermark::new(free, 0, free)] {
+            assert_eq!(mgr.set_watermark(ZoneId::KERNEL, mark), Err(MemoryError::InvalidWatermark));
+        }
+        let page : Vec<_> = core::iter::from_fn(|| mgr.kernel_page(1)).collect();
+        assert_eq!(page.len(), free - 4);
+        for addr in page {
+            mgr.free_page(addr);
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! # 内存区域编号
+//! 物理内存按地址从低到高划分为若干区域，每个区域有自己的页面数组、内存池链表与锁
+//! 0 号区域为 DMA 专用区域，占据最低的地址；1 号为内核区域，开头存放所有区域的页面数组；
+//! 2 号一般为用户区域
+//!
+//! 2021年4月30日 zg
+
+use crate::config::DMA_PAGE_NUM;
+
+/// ## 区域编号
+/// 即区域在地址上的次序，相邻区域之间的边界可以移动
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ZoneId(pub usize);
+
+impl ZoneId {
+    /// 供设备直接访问的页面，边界固定，不借给其它区域
+    pub const DMA : Self = Self(0);
+    pub const KERNEL : Self = Self(1);
+    pub const USER : Self = Self(2);
+
+    pub const fn val(self)->usize {
+        self.0
+    }
+
+    pub fn is_kernel(self)->bool {
+        self == Self::KERNEL
+    }
+}
+
+/// ## DMA 区域默认的页面数
+/// 取 DMA_PAGE_NUM，但不超过管理范围的八分之一
+pub(crate) fn default_dma_page_num(total_num : usize)->usize {
+    DMA_PAGE_NUM.min(total_num / 8)
+}